@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::configure().build_client(false).compile(
+        &["proto/admin/v1/admin.proto", "proto/queue/v1/queue.proto"],
+        &["proto"],
+    )?;
+
+    Ok(())
+}
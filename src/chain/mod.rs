@@ -0,0 +1,314 @@
+//! Access to the UTXO contract on chain.
+//!
+//! [`UtxoContract`] wraps the contract bindings and adds calls that the bindings
+//! don't provide, like the dry run of the shuffle transfer and its submission
+//! with EIP-1559 fees from the pool of service accounts, which are made directly
+//! through the provider. Transactions are signed in process or by the remote
+//! signer, depending on the signer backend.
+mod signer;
+mod signers;
+mod transfer;
+
+use std::time::Duration;
+
+use coin_shuffle_contracts_bindings::utxo::{
+    self,
+    types::{Input, Output, Utxo},
+    Contract,
+};
+use ethers_core::{
+    abi::{self, Token},
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Bytes, RecoveryMessage, Signature,
+        TransactionRequest, U256,
+    },
+    utils::{
+        hex::{self, ToHex},
+        keccak256,
+    },
+};
+use ethers_middleware::SignerMiddleware;
+use ethers_providers::{Http, JsonRpcError, Middleware, Provider, ProviderError, RpcError};
+use ethers_signers::LocalWallet;
+use eyre::Context;
+
+use crate::config::{ContractConfig, FeesConfig, SignerConfig};
+
+use self::signers::SignerPool;
+pub use self::transfer::{PendingTransfer, TransferOutcome, TransferPoll, TransferStatus};
+
+/// Signature of the contract method the shuffle transaction calls.
+const TRANSFER_SIGNATURE: &str = "transfer((uint256,bytes)[],(uint256,address)[])";
+
+/// How many times the transfer is simulated when the node fails to answer.
+const SIMULATION_ATTEMPTS: usize = 3;
+const SIMULATION_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// JSON-RPC error code of the reverted call with the revert data.
+const EXECUTION_ERROR_CODE: i64 = 3;
+/// JSON-RPC error code nodes use for the reverted call without the revert data,
+/// among other server errors.
+const SERVER_ERROR_CODE: i64 = -32000;
+
+#[derive(Clone)]
+pub struct UtxoContract {
+    connector: utxo::Connector<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    provider: Provider<Http>,
+    address: Address,
+    chain_id: u64,
+    /// Accounts the service sends transactions from.
+    signers: SignerPool,
+    fees: FeesConfig,
+    /// Number of blocks, including the one with the transaction, after which
+    /// the transaction is considered final.
+    confirmations: u64,
+}
+
+pub struct SignerBalances {
+    /// Balance of every service account.
+    pub accounts: Vec<(Address, U256)>,
+    /// Total balance of the accounts that are not out of rotation.
+    pub available: U256,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SimulationError {
+    /// Contract rejected the transfer, with the revert reason returned by the node.
+    #[error("transfer is reverted: {0}")]
+    Reverted(String),
+    #[error("failed to simulate transfer: {0}")]
+    Provider(#[from] ProviderError),
+}
+
+impl UtxoContract {
+    pub async fn connect(
+        cfg: &ContractConfig,
+        fees: &FeesConfig,
+        signer: &SignerConfig,
+    ) -> eyre::Result<Self> {
+        let (url, address) = (&cfg.url, cfg.address);
+
+        let signers = SignerPool::new(signer, cfg.chain_id)?;
+
+        let provider = Provider::<Http>::try_from(url.as_str())
+            .wrap_err_with(|| format!("failed to init provider: {url}"))?;
+
+        // Connector is used only for reads, so it's created with the throwaway key
+        // instead of the service one, that may be not available in process.
+        let throwaway_key = LocalWallet::new(&mut rand::thread_rng())
+            .signer()
+            .to_bytes();
+
+        let connector = utxo::Connector::with_priv_key(
+            url.to_string(),
+            address.encode_hex(),
+            hex::encode(throwaway_key),
+        )
+        .await
+        .context("failed to init contract connector")?;
+
+        let contract = Self {
+            connector,
+            provider,
+            address,
+            chain_id: cfg.chain_id,
+            signers,
+            fees: fees.clone(),
+            confirmations: cfg.confirmations,
+        };
+
+        for account in contract.signers.accounts() {
+            let address = account.signer.address();
+            let nonce = contract.pending_nonce(address).await?;
+
+            log::info!("next nonce of the service account {address:?} is {nonce}");
+            *account.nonce.lock().await = Some(nonce);
+        }
+
+        Ok(contract)
+    }
+
+    pub async fn get_utxo_by_id(&self, utxo_id: U256) -> eyre::Result<Option<Utxo>> {
+        self.connector.get_utxo_by_id(utxo_id).await
+    }
+
+    /// Returns balances of the service accounts and their total balance that
+    /// can be spent on transactions.
+    pub async fn signer_balances(&self) -> eyre::Result<SignerBalances> {
+        let (accounts, available) = self.signers.balances(&self.provider).await?;
+
+        Ok(SignerBalances {
+            accounts,
+            available,
+        })
+    }
+
+    /// Highest cost of the shuffle transaction of one room, with fees at the caps.
+    pub fn transfer_cost(&self) -> U256 {
+        self.fees.max_fee_per_gas * self.fees.transfer_gas
+    }
+
+    /// Dry runs the transfer with `eth_call` from the service account, so the
+    /// transaction that would revert on chain is not sent.
+    ///
+    /// Other errors of the node, like rate limits or unknown block, say nothing
+    /// about the transfer, so the call is retried and they are not reported as
+    /// reverts the room is blamed for.
+    pub async fn simulate_transfer(
+        &self,
+        inputs: &[Input],
+        outputs: &[Output],
+    ) -> Result<(), SimulationError> {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .from(self.signers.default_address())
+            .to(self.address)
+            .data(transfer_calldata(inputs, outputs))
+            .into();
+
+        let mut attempt = 1;
+
+        loop {
+            let Err(err) = self.provider.call(&tx, None).await else {
+                return Ok(());
+            };
+
+            match err.as_error_response() {
+                Some(response) if is_revert(response) => {
+                    return Err(SimulationError::Reverted(response.message.clone()));
+                }
+                _ if attempt < SIMULATION_ATTEMPTS => {
+                    log::warn!("failed to simulate transfer, attempt {attempt}: {err}");
+                    attempt += 1;
+                    tokio::time::sleep(SIMULATION_RETRY_DELAY).await;
+                }
+                _ => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Returns `true` if the JSON-RPC error is the revert of the called contract.
+fn is_revert(error: &JsonRpcError) -> bool {
+    error.code == EXECUTION_ERROR_CODE
+        || (error.code == SERVER_ERROR_CODE && error.message.contains("execution reverted"))
+}
+
+/// Returns address that signed the transfer `outputs` for the input.
+///
+/// Owner of the input signs the hash of the input id and the outputs as the
+/// Ethereum signed message, the same way the contract checks it in `transfer`.
+pub fn recover_input_signer(
+    utxo_id: U256,
+    outputs: &[Output],
+    signature: &[u8],
+) -> eyre::Result<Address> {
+    let signature = Signature::try_from(signature).context("malformed signature")?;
+
+    let hash = keccak256(abi::encode(&[Token::Uint(utxo_id), outputs_token(outputs)]));
+
+    signature
+        .recover(RecoveryMessage::Data(hash.to_vec()))
+        .context("failed to recover signer")
+}
+
+fn transfer_calldata(inputs: &[Input], outputs: &[Output]) -> Bytes {
+    let inputs = Token::Array(
+        inputs
+            .iter()
+            .map(|input| {
+                Token::Tuple(vec![
+                    Token::Uint(input.id),
+                    Token::Bytes(input.signature.to_vec()),
+                ])
+            })
+            .collect(),
+    );
+
+    let mut calldata = keccak256(TRANSFER_SIGNATURE)[..4].to_vec();
+    calldata.extend(abi::encode(&[inputs, outputs_token(outputs)]));
+
+    calldata.into()
+}
+
+fn outputs_token(outputs: &[Output]) -> Token {
+    Token::Array(
+        outputs
+            .iter()
+            .map(|output| {
+                Token::Tuple(vec![
+                    Token::Uint(output.amount),
+                    Token::Address(output.owner),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// First of the well-known development accounts.
+    const SIGNER: &str = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+    const UTXO_ID: u64 = 42;
+
+    /// Signature of the outputs below as the client signs them: personal
+    /// message of the hash of the ABI-encoded utxo id and outputs.
+    const SIGNATURE: &str = "ad6da2992898204fd75a786adbd046de5e5b587983902f16d9a644a59461338d6f5e3dc316fb8446982e0718ed1723cb8624d394efc00bf6e01ef034d6da8fbe1b";
+
+    fn outputs() -> Vec<Output> {
+        [
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+            "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC",
+        ]
+        .into_iter()
+        .map(|owner| Output {
+            amount: U256::exp10(18),
+            owner: Address::from_str(owner).unwrap(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn recovers_signer_of_outputs() {
+        let signature = hex::decode(SIGNATURE).unwrap();
+
+        let signer = recover_input_signer(UTXO_ID.into(), &outputs(), &signature).unwrap();
+
+        assert_eq!(signer, Address::from_str(SIGNER).unwrap());
+    }
+
+    #[test]
+    fn recovers_other_signer_of_changed_outputs() {
+        let signature = hex::decode(SIGNATURE).unwrap();
+        let mut outputs = outputs();
+        outputs.swap(0, 1);
+
+        let signer = recover_input_signer(UTXO_ID.into(), &outputs, &signature).unwrap();
+
+        assert_ne!(signer, Address::from_str(SIGNER).unwrap());
+    }
+
+    fn rpc_error(code: i64, message: &str) -> JsonRpcError {
+        JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn treats_execution_errors_as_reverts() {
+        assert!(is_revert(&rpc_error(3, "execution reverted: UTXO: spent")));
+        assert!(is_revert(&rpc_error(-32000, "execution reverted")));
+    }
+
+    #[test]
+    fn doesnt_treat_node_errors_as_reverts() {
+        assert!(!is_revert(&rpc_error(-32005, "limit exceeded")));
+        assert!(!is_revert(&rpc_error(-32000, "header not found")));
+        assert!(!is_revert(&rpc_error(-32603, "internal error")));
+    }
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use ethers_core::{
+    types::{transaction::eip2718::TypedTransaction, Address, Bytes},
+    utils::rlp::Rlp,
+};
+use ethers_providers::{Http, Provider};
+use ethers_signers::{LocalWallet, Signer};
+use eyre::{eyre, Context};
+
+/// Signer of the transactions of one service account.
+#[async_trait]
+pub(super) trait TransactionSigner: Send + Sync {
+    /// Address of the account.
+    fn address(&self) -> Address;
+
+    /// Returns the signed transaction encoded the way it's sent to the node.
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> eyre::Result<Bytes>;
+}
+
+/// Signs with the key kept in the service memory.
+pub(super) struct LocalSigner {
+    wallet: LocalWallet,
+}
+
+impl LocalSigner {
+    pub(super) fn new(wallet: LocalWallet) -> Self {
+        Self { wallet }
+    }
+}
+
+#[async_trait]
+impl TransactionSigner for LocalSigner {
+    fn address(&self) -> Address {
+        self.wallet.address()
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> eyre::Result<Bytes> {
+        let signature = self
+            .wallet
+            .sign_transaction(tx)
+            .await
+            .context("failed to sign transaction")?;
+
+        Ok(tx.rlp_signed(&signature))
+    }
+}
+
+/// Signs with the key kept by the separate signer process, which is asked over
+/// JSON-RPC with `eth_signTransaction`.
+pub(super) struct RemoteSigner {
+    provider: Provider<Http>,
+    address: Address,
+}
+
+/// Response to `eth_signTransaction`: some signers return just the raw signed
+/// transaction, others return it along with the decoded one.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum SignedTransaction {
+    Raw(Bytes),
+    Object { raw: Bytes },
+}
+
+impl RemoteSigner {
+    pub(super) fn new(provider: Provider<Http>, address: Address) -> Self {
+        Self { provider, address }
+    }
+}
+
+#[async_trait]
+impl TransactionSigner for RemoteSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> eyre::Result<Bytes> {
+        let signed: SignedTransaction = self
+            .provider
+            .request("eth_signTransaction", [tx])
+            .await
+            .wrap_err_with(|| format!("remote signer failed to sign for {:?}", self.address))?;
+
+        let raw = match signed {
+            SignedTransaction::Raw(raw) | SignedTransaction::Object { raw } => raw,
+        };
+
+        // The signer is not trusted to sign what it's asked to with the right
+        // key, so the transaction is not sent unless it's checked.
+        let (signed_tx, signature) = TypedTransaction::decode_signed(&Rlp::new(&raw))
+            .context("remote signer returned malformed transaction")?;
+
+        let signer = signature
+            .recover(signed_tx.sighash())
+            .context("remote signer returned invalid signature")?;
+
+        if signer != self.address || signed_tx.sighash() != tx.sighash() {
+            return Err(eyre!(
+                "remote signer returned transaction that differs from the requested one"
+            ));
+        }
+
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr, str::FromStr, sync::Arc};
+
+    use ethers_core::types::Eip1559TransactionRequest;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Key of the first well-known development account and of some other one.
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9c86dae88c7a8412f4603b6b78690d";
+    const CHAIN_ID: u64 = 5;
+
+    type Respond = Arc<dyn Fn(TypedTransaction) -> Value + Send + Sync>;
+
+    /// Starts JSON-RPC server that answers `eth_signTransaction` with the
+    /// result of `respond` for the requested transaction.
+    fn stub_signer(
+        respond: impl Fn(TypedTransaction) -> Value + Send + Sync + 'static,
+    ) -> Provider<Http> {
+        let respond: Respond = Arc::new(respond);
+
+        let make_service = make_service_fn(move |_conn| {
+            let respond = respond.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(respond.clone(), request)))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        Provider::try_from(url).unwrap()
+    }
+
+    async fn handle(
+        respond: Respond,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(request["method"], "eth_signTransaction");
+        let mut tx: TypedTransaction =
+            serde_json::from_value(request["params"][0].clone()).unwrap();
+        // Chain id is not sent, the signer signs for the chain it's configured for.
+        tx.set_chain_id(CHAIN_ID);
+
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": respond(tx),
+        });
+
+        Ok(Response::new(Body::from(response.to_string())))
+    }
+
+    fn wallet(key: &str) -> LocalWallet {
+        LocalWallet::from_str(key).unwrap()
+    }
+
+    fn sign(key: &str, tx: &TypedTransaction) -> Bytes {
+        let signature = wallet(key).sign_transaction_sync(tx).unwrap();
+
+        tx.rlp_signed(&signature)
+    }
+
+    fn transaction() -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .to(wallet(OTHER_KEY).address())
+            .value(1)
+            .nonce(7)
+            .gas(21_000)
+            .max_fee_per_gas(2_000_000_000u64)
+            .max_priority_fee_per_gas(100_000_000u64)
+            .chain_id(CHAIN_ID)
+            .into()
+    }
+
+    #[tokio::test]
+    async fn accepts_raw_transaction() {
+        let provider = stub_signer(|tx| json!(sign(KEY, &tx)));
+        let signer = RemoteSigner::new(provider, wallet(KEY).address());
+        let tx = transaction();
+
+        let raw = signer.sign_transaction(&tx).await.unwrap();
+
+        assert_eq!(raw, sign(KEY, &tx));
+    }
+
+    #[tokio::test]
+    async fn accepts_raw_transaction_with_decoded_one() {
+        let provider = stub_signer(|tx| json!({ "raw": sign(KEY, &tx), "tx": tx }));
+        let signer = RemoteSigner::new(provider, wallet(KEY).address());
+        let tx = transaction();
+
+        let raw = signer.sign_transaction(&tx).await.unwrap();
+
+        assert_eq!(raw, sign(KEY, &tx));
+    }
+
+    #[tokio::test]
+    async fn rejects_transaction_signed_by_other_account() {
+        let provider = stub_signer(|tx| json!(sign(OTHER_KEY, &tx)));
+        let signer = RemoteSigner::new(provider, wallet(KEY).address());
+
+        let result = signer.sign_transaction(&transaction()).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_changed_transaction() {
+        let provider = stub_signer(|mut tx| {
+            tx.set_value(2);
+            json!(sign(KEY, &tx))
+        });
+        let signer = RemoteSigner::new(provider, wallet(KEY).address());
+
+        let result = signer.sign_transaction(&transaction()).await;
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use ethers_core::types::{Address, U256};
+use ethers_providers::{Http, Middleware, Provider};
+use ethers_signers::{LocalWallet, Signer};
+use eyre::Context;
+use tokio::sync::Mutex;
+
+use crate::config::{SignerBackend, SignerConfig, SignerSelection};
+
+use super::signer::{LocalSigner, RemoteSigner, TransactionSigner};
+
+/// Account the service sends transactions from.
+pub(super) struct Account {
+    pub(super) signer: Box<dyn TransactionSigner>,
+    /// Next nonce of the account, shared by all rooms, so transactions of rooms
+    /// that finish at the same time don't take the same nonce. `None` when it
+    /// should be loaded from the node again.
+    pub(super) nonce: Mutex<Option<U256>>,
+    /// Number of sent transactions that are not final yet.
+    pending: AtomicUsize,
+}
+
+/// Accounts the transactions of rooms are distributed between, so a single key
+/// doesn't pay for every room and rooms don't wait for each other's nonces.
+#[derive(Clone)]
+pub(super) struct SignerPool {
+    accounts: Arc<Vec<Account>>,
+    selection: SignerSelection,
+    /// Account with lower balance is not selected for new transactions.
+    min_balance: U256,
+    /// Index of the account the next round-robin selection starts from.
+    next: Arc<AtomicUsize>,
+}
+
+impl SignerPool {
+    pub(super) fn new(cfg: &SignerConfig, chain_id: u64) -> eyre::Result<Self> {
+        let signers: Vec<Box<dyn TransactionSigner>> = match &cfg.backend {
+            SignerBackend::Local { private_keys } => private_keys
+                .iter()
+                .enumerate()
+                .map(|(index, private_key)| {
+                    let wallet = LocalWallet::from_str(private_key)
+                        .wrap_err_with(|| format!("failed to parse signer private key #{index}"))?
+                        .with_chain_id(chain_id);
+
+                    Ok(Box::new(LocalSigner::new(wallet)) as Box<dyn TransactionSigner>)
+                })
+                .collect::<eyre::Result<_>>()?,
+            SignerBackend::Remote { url, addresses } => {
+                let provider = Provider::<Http>::try_from(url.as_str())
+                    .wrap_err_with(|| format!("failed to init remote signer provider: {url}"))?;
+
+                addresses
+                    .iter()
+                    .map(|address| {
+                        Box::new(RemoteSigner::new(provider.clone(), *address))
+                            as Box<dyn TransactionSigner>
+                    })
+                    .collect()
+            }
+        };
+
+        let accounts: Vec<Account> = signers
+            .into_iter()
+            .map(|signer| Account {
+                signer,
+                nonce: Mutex::new(None),
+                pending: AtomicUsize::new(0),
+            })
+            .collect();
+
+        eyre::ensure!(!accounts.is_empty(), "no signer private keys");
+
+        Ok(Self {
+            accounts: Arc::new(accounts),
+            selection: cfg.selection,
+            min_balance: cfg.min_balance,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub(super) fn get(&self, index: usize) -> &Account {
+        &self.accounts[index]
+    }
+
+    pub(super) fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    /// Returns balances of all accounts and the total balance of the accounts
+    /// in rotation.
+    pub(super) async fn balances(
+        &self,
+        provider: &Provider<Http>,
+    ) -> eyre::Result<(Vec<(Address, U256)>, U256)> {
+        let mut balances = Vec::with_capacity(self.accounts.len());
+        let mut available = U256::zero();
+
+        for account in self.accounts.iter() {
+            let address = account.signer.address();
+
+            let balance = provider
+                .get_balance(address, None)
+                .await
+                .wrap_err_with(|| format!("failed to get balance of signer {address:?}"))?;
+
+            if balance >= self.min_balance {
+                available = available.saturating_add(balance);
+            }
+
+            balances.push((address, balance));
+        }
+
+        Ok((balances, available))
+    }
+
+    /// Address calls that don't change the state are made from.
+    pub(super) fn default_address(&self) -> Address {
+        self.accounts[0].signer.address()
+    }
+
+    /// Selects the account for the new transaction, skipping accounts with the
+    /// balance below the minimum. Returns index of the account.
+    pub(super) async fn select(&self, provider: &Provider<Http>) -> eyre::Result<usize> {
+        let count = self.accounts.len();
+
+        let order: Vec<usize> = match self.selection {
+            SignerSelection::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|offset| (start + offset) % count).collect()
+            }
+            SignerSelection::LeastBusy => {
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by_key(|index| self.accounts[*index].pending.load(Ordering::Relaxed));
+                order
+            }
+        };
+
+        for index in order {
+            let address = self.accounts[index].signer.address();
+
+            // Account that can't be checked is skipped, others may still be fine.
+            let balance = match provider.get_balance(address, None).await {
+                Ok(balance) => balance,
+                Err(err) => {
+                    log::warn!("failed to get balance of signer {address:?}, skipping it: {err}");
+                    continue;
+                }
+            };
+
+            if balance >= self.min_balance {
+                return Ok(index);
+            }
+
+            log::warn!(
+                "signer {address:?} is out of rotation: balance {balance} is below {}",
+                self.min_balance
+            );
+        }
+
+        eyre::bail!(
+            "no signer has known balance of at least {}",
+            self.min_balance
+        )
+    }
+
+    /// Marks that the transaction of the account is sent.
+    pub(super) fn started(&self, index: usize) {
+        self.accounts[index].pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks that the transaction of the account is final.
+    pub(super) fn finished(&self, index: usize) {
+        self.accounts[index].pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
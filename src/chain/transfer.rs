@@ -0,0 +1,397 @@
+use std::{str::FromStr, time::Duration};
+
+use coin_shuffle_contracts_bindings::utxo::types::{Input, Output};
+use ethers_core::types::{
+    transaction::eip2718::TypedTransaction, Address, BlockNumber, Eip1559TransactionRequest, H256,
+    U256,
+};
+use ethers_providers::{Middleware, ProviderError, RpcError};
+use eyre::Context;
+use tokio::time::Instant;
+
+use super::{transfer_calldata, UtxoContract};
+
+/// Transaction that the node doesn't know about for that long is considered dropped.
+const DROPPED_TIMEOUT: Duration = Duration::from_secs(600);
+/// How many times the transaction is sent again with the nonce loaded from the
+/// node, when the node reports that the nonce is already used.
+const NONCE_RETRIES: usize = 3;
+/// Priority fee a zero one is raised to on resubmission, in wei.
+const MIN_PRIORITY_FEE: u64 = 100_000_000;
+
+/// State of the sent shuffle transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    /// Transaction is sent, but doesn't have enough confirmations yet.
+    Pending,
+    /// Transaction is mined successfully and has enough confirmations.
+    Confirmed,
+    /// Transaction is mined, but reverted by the contract.
+    Reverted,
+    /// Transaction is not known to the node anymore.
+    Dropped,
+}
+
+impl TransferStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferStatus::Pending => "pending",
+            TransferStatus::Confirmed => "confirmed",
+            TransferStatus::Reverted => "reverted",
+            TransferStatus::Dropped => "dropped",
+        }
+    }
+}
+
+impl FromStr for TransferStatus {
+    type Err = eyre::Error;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "pending" => Ok(TransferStatus::Pending),
+            "confirmed" => Ok(TransferStatus::Confirmed),
+            "reverted" => Ok(TransferStatus::Reverted),
+            "dropped" => Ok(TransferStatus::Dropped),
+            _ => Err(eyre::eyre!("unknown transfer status: {status}")),
+        }
+    }
+}
+
+/// Final state of the sent shuffle transaction.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferOutcome {
+    pub status: TransferStatus,
+    /// Hash of the transaction that is mined, or of the latest sent one.
+    pub tx_hash: H256,
+    /// Block the transaction is included in, if it's mined.
+    pub block_number: Option<u64>,
+}
+
+/// Shuffle transaction that is sent, but not final yet.
+#[derive(Debug, Clone)]
+pub struct PendingTransfer {
+    /// Index of the account in the signer pool the transaction is sent from,
+    /// `None` for transactions that were sent before the restart.
+    signer: Option<usize>,
+    /// Latest sent transaction, `None` for transactions that were sent before
+    /// the restart and can't be resubmitted.
+    tx: Option<Eip1559TransactionRequest>,
+    /// Hashes of the transaction and its replacements, the latest is the last.
+    hashes: Vec<H256>,
+    /// Block number at the time the latest replacement was sent.
+    sent_at_block: u64,
+    /// Last time the node knew about the latest replacement.
+    last_seen: Instant,
+}
+
+impl PendingTransfer {
+    /// Transaction that was sent before the restart, it's only watched.
+    pub fn resumed(tx_hash: H256) -> Self {
+        Self {
+            signer: None,
+            tx: None,
+            hashes: vec![tx_hash],
+            sent_at_block: 0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Hash of the latest sent transaction.
+    pub fn tx_hash(&self) -> H256 {
+        self.hashes.last().cloned().unwrap_or_default()
+    }
+}
+
+/// Result of a single check of the pending transfer.
+#[derive(Debug)]
+pub enum TransferPoll {
+    /// Transaction is not final yet.
+    Pending,
+    /// Transaction is not mined in time and is resubmitted with bumped fees
+    /// under the new hash.
+    Replaced(H256),
+    Final(TransferOutcome),
+}
+
+impl UtxoContract {
+    /// Sends the shuffle transaction from the account selected in the signer pool
+    /// with EIP-1559 fees estimated by the node and capped by the fees config.
+    ///
+    /// Transactions of the same account are sent one by one with its shared nonce,
+    /// which is advanced right after the transaction is accepted by the node, so
+    /// the next one doesn't wait until the previous one is mined.
+    pub async fn send_transfer(
+        &self,
+        inputs: &[Input],
+        outputs: &[Output],
+    ) -> eyre::Result<PendingTransfer> {
+        let (max_fee, priority_fee) = self
+            .provider
+            .estimate_eip1559_fees(None)
+            .await
+            .context("failed to estimate fees")?;
+        let max_fee = max_fee.min(self.fees.max_fee_per_gas);
+        let priority_fee = priority_fee
+            .min(self.fees.max_priority_fee_per_gas)
+            .min(max_fee);
+
+        let signer = self.signers.select(&self.provider).await?;
+
+        let tx = Eip1559TransactionRequest::new()
+            .from(self.signers.get(signer).signer.address())
+            .to(self.address)
+            .data(transfer_calldata(inputs, outputs))
+            .chain_id(self.chain_id)
+            .max_fee_per_gas(max_fee)
+            .max_priority_fee_per_gas(priority_fee);
+
+        let gas = self
+            .provider
+            .estimate_gas(&tx.clone().into(), None)
+            .await
+            .context("failed to estimate gas")?;
+
+        // Head is read before sending, as nothing may fail after the transaction
+        // is out, or it would be left untracked.
+        let sent_at_block = self.block_number().await?;
+
+        let (tx, tx_hash) = self.send_with_next_nonce(signer, tx.gas(gas)).await?;
+        self.signers.started(signer);
+
+        Ok(PendingTransfer {
+            signer: Some(signer),
+            tx: Some(tx),
+            hashes: vec![tx_hash],
+            sent_at_block,
+            last_seen: Instant::now(),
+        })
+    }
+
+    /// Checks the transfer once: whether any of its transactions is final, or
+    /// it's not mined for `resubmit_after_blocks` and should be resubmitted with
+    /// the same nonce and bumped fees.
+    pub async fn poll_transfer(
+        &self,
+        transfer: &mut PendingTransfer,
+    ) -> eyre::Result<TransferPoll> {
+        let head = self.block_number().await?;
+
+        // Replaced transaction can still be mined instead of the latest one.
+        for tx_hash in transfer.hashes.iter().rev() {
+            let Some(receipt) = self.provider.get_transaction_receipt(*tx_hash).await? else {
+                continue;
+            };
+            let Some(block_number) = receipt.block_number.map(|block| block.as_u64()) else {
+                continue;
+            };
+
+            transfer.last_seen = Instant::now();
+
+            if head.saturating_sub(block_number) + 1 < self.confirmations {
+                return Ok(TransferPoll::Pending);
+            }
+
+            let status = if receipt.status == Some(1u64.into()) {
+                TransferStatus::Confirmed
+            } else {
+                TransferStatus::Reverted
+            };
+
+            let outcome = TransferOutcome {
+                status,
+                tx_hash: *tx_hash,
+                block_number: Some(block_number),
+            };
+
+            return Ok(self.finish(transfer, outcome));
+        }
+
+        if self
+            .provider
+            .get_transaction(transfer.tx_hash())
+            .await?
+            .is_some()
+        {
+            transfer.last_seen = Instant::now();
+        } else if transfer.last_seen.elapsed() > DROPPED_TIMEOUT {
+            // Transactions sent after the dropped one are stuck behind the nonce
+            // gap, so the next one takes the nonce from the node.
+            if let Some(signer) = transfer.signer {
+                *self.signers.get(signer).nonce.lock().await = None;
+            }
+
+            let outcome = TransferOutcome {
+                status: TransferStatus::Dropped,
+                tx_hash: transfer.tx_hash(),
+                block_number: None,
+            };
+
+            return Ok(self.finish(transfer, outcome));
+        }
+
+        if head < transfer.sent_at_block + self.fees.resubmit_after_blocks {
+            return Ok(TransferPoll::Pending);
+        }
+
+        self.resubmit(transfer, head).await
+    }
+
+    /// Releases the account of the final transfer in the signer pool.
+    fn finish(&self, transfer: &PendingTransfer, outcome: TransferOutcome) -> TransferPoll {
+        if let Some(signer) = transfer.signer {
+            self.signers.finished(signer);
+        }
+
+        TransferPoll::Final(outcome)
+    }
+
+    async fn resubmit(
+        &self,
+        transfer: &mut PendingTransfer,
+        head: u64,
+    ) -> eyre::Result<TransferPoll> {
+        let (Some(signer), Some(tx)) = (transfer.signer, transfer.tx.as_ref()) else {
+            return Ok(TransferPoll::Pending);
+        };
+
+        let max_fee = tx.max_fee_per_gas.unwrap_or_default();
+        let priority_fee = tx.max_priority_fee_per_gas.unwrap_or_default();
+
+        let bumped_max_fee = bump(max_fee, self.fees.bump_percent);
+        let bumped_priority_fee =
+            bump(priority_fee, self.fees.bump_percent).max(MIN_PRIORITY_FEE.into());
+
+        // Next resubmission is tried after another `resubmit_after_blocks`,
+        // whether this one is sent or not.
+        transfer.sent_at_block = head;
+
+        // Node doesn't accept the replacement without the bump of both fees.
+        if bumped_max_fee > self.fees.max_fee_per_gas
+            || bumped_priority_fee > self.fees.max_priority_fee_per_gas
+            || bumped_priority_fee > bumped_max_fee
+        {
+            log::warn!(
+                "transaction {:?} is not mined, but its fees can't be bumped by {}% within the caps",
+                transfer.tx_hash(),
+                self.fees.bump_percent,
+            );
+            return Ok(TransferPoll::Pending);
+        }
+
+        let tx = tx
+            .clone()
+            .max_fee_per_gas(bumped_max_fee)
+            .max_priority_fee_per_gas(bumped_priority_fee);
+
+        let tx_hash = match self.send_signed(signer, &tx).await {
+            Ok(tx_hash) => tx_hash,
+            // Nonce is already used, so one of the sent transactions is mined
+            // and its receipt is found on the next poll.
+            Err(err) if is_nonce_too_low(&err) => return Ok(TransferPoll::Pending),
+            Err(err) => return Err(err),
+        };
+
+        transfer.tx = Some(tx);
+        transfer.hashes.push(tx_hash);
+        transfer.last_seen = Instant::now();
+
+        Ok(TransferPoll::Replaced(tx_hash))
+    }
+
+    /// Sends the transaction with the next nonce of the account. Returns the sent
+    /// transaction with its hash.
+    async fn send_with_next_nonce(
+        &self,
+        signer: usize,
+        tx: Eip1559TransactionRequest,
+    ) -> eyre::Result<(Eip1559TransactionRequest, H256)> {
+        let account = self.signers.get(signer);
+        let mut next_nonce = account.nonce.lock().await;
+        let mut attempt = 0;
+
+        loop {
+            let nonce = match *next_nonce {
+                Some(nonce) => nonce,
+                None => self.pending_nonce(account.signer.address()).await?,
+            };
+
+            let tx = tx.clone().nonce(nonce);
+
+            match self.send_signed(signer, &tx).await {
+                Ok(tx_hash) => {
+                    *next_nonce = Some(nonce + 1);
+                    return Ok((tx, tx_hash));
+                }
+                // Nonce is taken by a transaction sent outside of the service.
+                Err(err) if is_nonce_too_low(&err) && attempt < NONCE_RETRIES => {
+                    log::warn!("nonce {nonce} is already used, loading it from the node");
+                    *next_nonce = None;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    // It's unknown whether the node has accepted the transaction.
+                    *next_nonce = None;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Next nonce of the account, including transactions in the mempool.
+    pub(super) async fn pending_nonce(&self, address: Address) -> eyre::Result<U256> {
+        self.provider
+            .get_transaction_count(address, Some(BlockNumber::Pending.into()))
+            .await
+            .wrap_err_with(|| format!("failed to get nonce of the service account {address:?}"))
+    }
+
+    async fn send_signed(
+        &self,
+        signer: usize,
+        tx: &Eip1559TransactionRequest,
+    ) -> eyre::Result<H256> {
+        let tx: TypedTransaction = tx.clone().into();
+
+        let raw = self
+            .signers
+            .get(signer)
+            .signer
+            .sign_transaction(&tx)
+            .await?;
+
+        let pending = self
+            .provider
+            .send_raw_transaction(raw)
+            .await
+            .context("failed to send transaction")?;
+
+        Ok(pending.tx_hash())
+    }
+
+    async fn block_number(&self) -> eyre::Result<u64> {
+        let block_number = self
+            .provider
+            .get_block_number()
+            .await
+            .context("failed to get block number")?;
+
+        Ok(block_number.as_u64())
+    }
+}
+
+/// Raises the fee by `percent`, rounding up, so the node never sees less than
+/// the required bump.
+fn bump(fee: U256, percent: u64) -> U256 {
+    (fee * (100 + percent) + 99) / 100
+}
+
+fn is_nonce_too_low(err: &eyre::Report) -> bool {
+    let response = err
+        .downcast_ref::<ProviderError>()
+        .and_then(|err| err.as_error_response());
+
+    match response {
+        Some(response) => response.message.contains("nonce too low"),
+        None => false,
+    }
+}
@@ -1,26 +1,58 @@
-use coin_shuffle_contracts_bindings::utxo;
+use std::sync::Arc;
+
 use coin_shuffle_protos::v1::shuffle_service_server::ShuffleServiceServer;
-use ethers_core::utils::hex::ToHex;
 use eyre::Context;
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
 use tonic::transport::Server;
 
-use crate::{config::Config as Cfg, service::Protocol};
+use crate::{
+    chain::UtxoContract,
+    config::{Config as Cfg, StorageConfig},
+    metrics::{self, RpcMetricsLayer},
+    proto::{
+        admin::v1::admin_service_server::AdminServiceServer,
+        queue::v1::queue_service_server::QueueServiceServer,
+    },
+    service::{AdminAuth, JoinSignatureVerifier, Protocol, RoomDeadlines},
+    storage::{MemoryStorage, SqliteStorage, Storage},
+    waiter::{OwnerLimit, WaiterOptions},
+};
 
 pub(super) async fn run_service(cfg: Cfg) -> eyre::Result<()> {
-    let contract = utxo::Connector::with_priv_key(
-        cfg.contract.url.to_string(),
-        cfg.contract.address.encode_hex(),
-        cfg.signer.private_key,
-    )
-    .await
-    .context("failed to init contract connector")?;
+    let contract = UtxoContract::connect(&cfg.contract, &cfg.fees, &cfg.signer).await?;
+
+    let storage: Arc<dyn Storage> = match cfg.storage {
+        StorageConfig::Memory => Arc::new(MemoryStorage::new()),
+        StorageConfig::Sqlite { path } => {
+            Arc::new(SqliteStorage::open(path).context("failed to open storage")?)
+        }
+    };
+
+    let join_verifier = JoinSignatureVerifier::new(
+        cfg.contract.chain_id,
+        cfg.contract.address,
+        cfg.service.legacy_join_signatures,
+        cfg.service.max_signature_age,
+    );
 
     let service = Protocol::new(
         contract,
         cfg.tokens.sign_key,
-        cfg.service.shuffle_round_deadline,
-        cfg.service.min_room_size,
+        RoomDeadlines {
+            connect: cfg.service.connect_deadline,
+            shuffle_round: cfg.service.shuffle_round_deadline,
+            signing: cfg.service.signing_deadline,
+        },
+        WaiterOptions {
+            min_participants: cfg.service.min_room_size,
+            max_participants: cfg.service.max_room_size,
+            formation_timeout: cfg.service.room_formation_timeout,
+            policies: vec![Box::new(OwnerLimit::new(cfg.service.max_utxos_per_owner))],
+            queue_ttl: cfg.service.queue_ttl,
+        },
+        storage,
+        join_verifier,
+        cfg.service.used_signatures_capacity,
     );
 
     TermLogger::init(
@@ -31,10 +63,26 @@ pub(super) async fn run_service(cfg: Cfg) -> eyre::Result<()> {
     )
     .unwrap();
 
-    Server::builder()
+    service.restore().await?;
+
+    let admin_server = Server::builder()
+        .add_service(AdminServiceServer::with_interceptor(
+            service.admin_service(),
+            AdminAuth::new(cfg.admin.token),
+        ))
+        .serve(std::net::SocketAddr::V4(cfg.admin.address));
+
+    let grpc_server = Server::builder()
+        .layer(RpcMetricsLayer)
+        .add_service(QueueServiceServer::new(service.queue_service()))
         .add_service(ShuffleServiceServer::new(service))
-        .serve(std::net::SocketAddr::V4(cfg.service.address))
-        .await?;
+        .serve(std::net::SocketAddr::V4(cfg.service.address));
+
+    tokio::try_join!(
+        async { grpc_server.await.context("gRPC server failed") },
+        async { admin_server.await.context("admin gRPC server failed") },
+        metrics::serve(std::net::SocketAddr::V4(cfg.metrics.address)),
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,69 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+};
+
+use eyre::Context;
+
+use super::secret;
+
+#[derive(serde::Deserialize)]
+pub(super) struct Raw {
+    address: String,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    token_env: Option<String>,
+}
+
+impl Raw {
+    /// Whether the token is set right in the config file.
+    pub(super) fn has_plaintext_token(&self) -> bool {
+        self.token.is_some()
+    }
+}
+
+pub struct Config {
+    pub address: SocketAddrV4,
+    /// Bearer token requests to the admin service should be authorized with,
+    /// `None` if the service is open to everyone who can reach the address.
+    pub token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8081),
+            token: None,
+        }
+    }
+}
+
+impl TryFrom<Raw> for Config {
+    type Error = eyre::Error;
+
+    fn try_from(raw: Raw) -> Result<Self, Self::Error> {
+        let address = raw
+            .address
+            .parse::<SocketAddrV4>()
+            .context("failed to parse admin addr")?;
+
+        let token = match (raw.token, raw.token_file, raw.token_env) {
+            (None, None, None) => None,
+            (Some(token), None, None) => Some(token),
+            (None, Some(path), None) => Some(secret::from_file(&path)?),
+            (None, None, Some(name)) => Some(secret::from_env(&name)?),
+            _ => eyre::bail!("only one of admin token, token_file or token_env can be set"),
+        };
+
+        if let Some(token) = &token {
+            eyre::ensure!(!token.is_empty(), "admin token must not be empty");
+        }
+
+        eyre::ensure!(
+            address.ip().is_loopback() || token.is_some(),
+            "admin service without token must listen on loopback address, got {address}"
+        );
+
+        Ok(Self { address, token })
+    }
+}
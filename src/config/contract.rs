@@ -7,11 +7,18 @@ use eyre::Context;
 pub(super) struct Raw {
     url: String,
     address: String,
+    chain_id: u64,
+    confirmations: u64,
 }
 
 pub struct Config {
     pub url: url::Url,
     pub address: Address,
+    /// Chain id the join signatures are bound to.
+    pub chain_id: u64,
+    /// Number of blocks, including the one with the shuffle transaction, after
+    /// which the transaction is considered final.
+    pub confirmations: u64,
 }
 
 impl Default for Config {
@@ -19,6 +26,8 @@ impl Default for Config {
         Self {
             url: url::Url::parse("http://localhost:8545").unwrap(),
             address: Address::default(),
+            chain_id: 1,
+            confirmations: 3,
         }
     }
 }
@@ -32,6 +41,13 @@ impl TryFrom<Raw> for Config {
         let address = Address::from_str(&raw.address)
             .wrap_err_with(|| format!("failed to parse address: {}", raw.address))?;
 
-        Ok(Self { url, address })
+        eyre::ensure!(raw.confirmations > 0, "confirmations must be positive");
+
+        Ok(Self {
+            url,
+            address,
+            chain_id: raw.chain_id,
+            confirmations: raw.confirmations,
+        })
     }
 }
@@ -0,0 +1,68 @@
+use ethers_core::types::U256;
+
+/// Number of wei in one gwei.
+const GWEI: u64 = 1_000_000_000;
+
+#[derive(serde::Deserialize)]
+pub(super) struct Raw {
+    max_fee_per_gas: u64,
+    max_priority_fee_per_gas: u64,
+    bump_percent: u64,
+    resubmit_after_blocks: u64,
+    transfer_gas: u64,
+}
+
+/// EIP-1559 fees of the shuffle transactions.
+#[derive(Clone)]
+pub struct Config {
+    /// Cap of the max fee per gas, in wei.
+    pub max_fee_per_gas: U256,
+    /// Cap of the max priority fee per gas, in wei.
+    pub max_priority_fee_per_gas: U256,
+    /// Percent both fees are raised by when the transaction is resubmitted.
+    pub bump_percent: u64,
+    /// Transaction that is not mined after that many blocks is resubmitted
+    /// with the same nonce and bumped fees.
+    pub resubmit_after_blocks: u64,
+    /// Gas the shuffle transaction of one room is expected to use, for the
+    /// estimate of the cost of finishing active rooms.
+    pub transfer_gas: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_fee_per_gas: U256::from(200 * GWEI),
+            max_priority_fee_per_gas: U256::from(3 * GWEI),
+            bump_percent: 20,
+            resubmit_after_blocks: 5,
+            transfer_gas: 500_000,
+        }
+    }
+}
+
+impl TryFrom<Raw> for Config {
+    type Error = eyre::Error;
+
+    fn try_from(raw: Raw) -> Result<Self, Self::Error> {
+        // Nodes don't accept replacements with less than 10% higher fees.
+        eyre::ensure!(raw.bump_percent >= 10, "bump_percent must be at least 10");
+        eyre::ensure!(
+            raw.resubmit_after_blocks > 0,
+            "resubmit_after_blocks must be positive"
+        );
+        eyre::ensure!(
+            raw.max_priority_fee_per_gas <= raw.max_fee_per_gas,
+            "max_priority_fee_per_gas must not be greater than max_fee_per_gas"
+        );
+        eyre::ensure!(raw.transfer_gas > 0, "transfer_gas must be positive");
+
+        Ok(Self {
+            max_fee_per_gas: U256::from(raw.max_fee_per_gas) * GWEI,
+            max_priority_fee_per_gas: U256::from(raw.max_priority_fee_per_gas) * GWEI,
+            bump_percent: raw.bump_percent,
+            resubmit_after_blocks: raw.resubmit_after_blocks,
+            transfer_gas: raw.transfer_gas,
+        })
+    }
+}
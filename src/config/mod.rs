@@ -1,19 +1,33 @@
+mod admin;
 mod contract;
+mod fees;
 mod logger;
+mod metrics;
+mod secret;
 mod service;
 mod signer;
+mod storage;
 mod tokens;
 
 use eyre::Context;
 use std::path::PathBuf;
 
+pub use contract::Config as ContractConfig;
+pub use fees::Config as FeesConfig;
+pub use signer::{Backend as SignerBackend, Config as SignerConfig, Selection as SignerSelection};
+pub use storage::Config as StorageConfig;
+
 #[derive(serde::Deserialize)]
 struct Raw {
     logger: logger::Raw,
     service: service::Raw,
     contract: contract::Raw,
+    fees: fees::Raw,
     signer: signer::Raw,
     tokens: tokens::Raw,
+    storage: storage::Raw,
+    metrics: metrics::Raw,
+    admin: admin::Raw,
 }
 
 #[derive(Default)]
@@ -21,20 +35,43 @@ pub struct Config {
     pub logger: logger::Config,
     pub service: service::Config,
     pub contract: contract::Config,
+    pub fees: fees::Config,
     pub signer: signer::Config,
     pub tokens: tokens::Config,
+    pub storage: storage::Config,
+    pub metrics: metrics::Config,
+    pub admin: admin::Config,
 }
 
 impl TryFrom<Raw> for Config {
     type Error = eyre::Error;
 
     fn try_from(raw: Raw) -> Result<Self, Self::Error> {
+        if raw.service.production {
+            eyre::ensure!(
+                !raw.signer.has_plaintext_key(),
+                "plaintext signer private key is not allowed in production, use private_key_file, private_key_env or keystore"
+            );
+            eyre::ensure!(
+                !raw.tokens.has_plaintext_key(),
+                "plaintext tokens sign_key is not allowed in production, use sign_key_file or sign_key_env"
+            );
+            eyre::ensure!(
+                !raw.admin.has_plaintext_token(),
+                "plaintext admin token is not allowed in production, use token_file or token_env"
+            );
+        }
+
         Ok(Self {
             logger: raw.logger.try_into()?,
             service: raw.service.try_into()?,
             contract: raw.contract.try_into()?,
+            fees: raw.fees.try_into()?,
             signer: raw.signer.try_into()?,
             tokens: raw.tokens.try_into()?,
+            storage: raw.storage.try_into()?,
+            metrics: raw.metrics.try_into()?,
+            admin: raw.admin.try_into()?,
         })
     }
 }
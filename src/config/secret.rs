@@ -0,0 +1,16 @@
+use std::{fs, path::Path};
+
+use eyre::Context;
+
+/// Reads the secret from the file, without surrounding whitespace.
+pub(super) fn from_file(path: &Path) -> eyre::Result<String> {
+    let secret = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read secret file: {}", path.display()))?;
+
+    Ok(secret.trim().to_string())
+}
+
+/// Reads the secret from the environment variable.
+pub(super) fn from_env(name: &str) -> eyre::Result<String> {
+    std::env::var(name).wrap_err_with(|| format!("failed to read secret env: {name}"))
+}
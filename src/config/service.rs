@@ -7,13 +7,42 @@ use eyre::Context;
 pub(super) struct Raw {
     address: String,
     min_room_size: usize,
+    max_room_size: usize,
+    room_formation_timeout: u64,
+    max_utxos_per_owner: usize,
+    queue_ttl: u64,
+    connect_deadline: u64,
     shuffle_round_deadline: u64,
+    signing_deadline: u64,
+    max_signature_age: u64,
+    legacy_join_signatures: bool,
+    used_signatures_capacity: usize,
+    /// Plaintext secrets in the config file are rejected.
+    pub(super) production: bool,
 }
 
 pub struct Config {
     pub address: SocketAddrV4,
     pub min_room_size: usize,
+    /// Room is formed right away when that many participants are queued.
+    pub max_room_size: usize,
+    /// Room with less than `max_room_size` participants is formed when the
+    /// oldest participant has waited that long.
+    pub room_formation_timeout: Duration,
+    /// Maximum number of UTXOs of the same owner in one room.
+    pub max_utxos_per_owner: usize,
+    /// Queued participants that haven't polled the service for longer are dropped.
+    pub queue_ttl: Duration,
+    pub connect_deadline: Duration,
     pub shuffle_round_deadline: Duration,
+    pub signing_deadline: Duration,
+    /// Join signatures with older timestamps are rejected.
+    pub max_signature_age: Duration,
+    /// Accept join signatures of raw utxo id and timestamp besides EIP-712 ones.
+    pub legacy_join_signatures: bool,
+    /// Maximum number of remembered join signatures used to reject replays. It
+    /// should cover joins for `max_signature_age`, as joins over it are refused.
+    pub used_signatures_capacity: usize,
 }
 
 impl Default for Config {
@@ -21,7 +50,16 @@ impl Default for Config {
         Self {
             address: SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080),
             min_room_size: 3,
+            max_room_size: 10,
+            room_formation_timeout: Duration::from_secs(60),
+            max_utxos_per_owner: 1,
+            queue_ttl: Duration::from_secs(600),
+            connect_deadline: Duration::from_secs(120),
             shuffle_round_deadline: Duration::from_secs(120),
+            signing_deadline: Duration::from_secs(120),
+            max_signature_age: Duration::from_secs(300),
+            legacy_join_signatures: true,
+            used_signatures_capacity: 100_000,
         }
     }
 }
@@ -35,12 +73,29 @@ impl TryFrom<Raw> for Config {
             .parse::<SocketAddrV4>()
             .context("failed to parse addr")?;
 
-        let shuffle_round_deadline = Duration::from_secs(raw.shuffle_round_deadline);
+        eyre::ensure!(raw.queue_ttl > 0, "queue_ttl must be positive");
+        eyre::ensure!(
+            raw.max_utxos_per_owner > 0,
+            "max_utxos_per_owner must be positive"
+        );
+        eyre::ensure!(
+            raw.max_room_size >= raw.min_room_size,
+            "max_room_size must not be less than min_room_size"
+        );
 
         Ok(Config {
             address,
-            shuffle_round_deadline,
+            connect_deadline: Duration::from_secs(raw.connect_deadline),
+            shuffle_round_deadline: Duration::from_secs(raw.shuffle_round_deadline),
+            signing_deadline: Duration::from_secs(raw.signing_deadline),
+            max_signature_age: Duration::from_secs(raw.max_signature_age),
+            legacy_join_signatures: raw.legacy_join_signatures,
+            used_signatures_capacity: raw.used_signatures_capacity,
             min_room_size: raw.min_room_size,
+            max_room_size: raw.max_room_size,
+            room_formation_timeout: Duration::from_secs(raw.room_formation_timeout),
+            max_utxos_per_owner: raw.max_utxos_per_owner,
+            queue_ttl: Duration::from_secs(raw.queue_ttl),
         })
     }
 }
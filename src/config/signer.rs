@@ -1,18 +1,176 @@
+use std::{path::PathBuf, str::FromStr};
+
+use ethers_core::{
+    types::{Address, U256},
+    utils::{hex, parse_ether},
+};
+use ethers_signers::LocalWallet;
+use eyre::{eyre, Context};
+
+use super::secret;
+
 #[derive(serde::Deserialize)]
 pub(super) struct Raw {
-    private_key: String,
+    private_key: Option<String>,
+    private_keys: Option<Vec<String>>,
+    private_key_file: Option<PathBuf>,
+    private_key_env: Option<String>,
+    /// Encrypted JSON keystore in the Ethereum V3 format.
+    keystore: Option<PathBuf>,
+    keystore_passphrase_file: Option<PathBuf>,
+    keystore_passphrase_env: Option<String>,
+    /// `local` or `remote`.
+    backend: Option<String>,
+    /// URL of the remote signer.
+    url: Option<String>,
+    /// Accounts of the remote signer the transactions are sent from.
+    addresses: Option<Vec<String>>,
+    selection: Option<String>,
+    min_balance: Option<String>,
+}
+
+impl Raw {
+    /// Whether any key is set right in the config file.
+    pub(super) fn has_plaintext_key(&self) -> bool {
+        self.private_key.is_some() || self.private_keys.is_some()
+    }
+
+    fn has_local_key(&self) -> bool {
+        self.has_plaintext_key()
+            || self.private_key_file.is_some()
+            || self.private_key_env.is_some()
+            || self.keystore.is_some()
+    }
 }
 
 #[derive(Default)]
 pub struct Config {
-    pub private_key: String,
+    /// Where the accounts the shuffle transactions are sent from are kept.
+    pub backend: Backend,
+    pub selection: Selection,
+    /// Account with lower balance, in wei, is taken out of rotation.
+    pub min_balance: U256,
+}
+
+pub enum Backend {
+    /// Keys are kept in the service memory.
+    Local { private_keys: Vec<String> },
+    /// Keys are kept by the separate signer process, that signs transactions
+    /// over JSON-RPC with `eth_signTransaction`.
+    Remote {
+        url: url::Url,
+        addresses: Vec<Address>,
+    },
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Local {
+            private_keys: Vec::new(),
+        }
+    }
+}
+
+/// How the account is selected for the transaction of the room.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Selection {
+    /// Accounts take turns.
+    #[default]
+    RoundRobin,
+    /// Account with the least number of transactions that are not final yet.
+    LeastBusy,
 }
 
 impl TryFrom<Raw> for Config {
     type Error = eyre::Error;
 
-    fn try_from(raw: Raw) -> Result<Self, Self::Error> {
-        let private_key = raw.private_key;
-        Ok(Self { private_key })
+    fn try_from(mut raw: Raw) -> Result<Self, Self::Error> {
+        let selection = match raw.selection.take().as_deref() {
+            None | Some("round_robin") => Selection::RoundRobin,
+            Some("least_busy") => Selection::LeastBusy,
+            Some(selection) => return Err(eyre!("unknown signer selection: {selection}")),
+        };
+
+        let min_balance = match raw.min_balance.take() {
+            Some(min_balance) => parse_ether(&min_balance)
+                .wrap_err_with(|| format!("failed to parse min_balance: {min_balance}"))?,
+            None => U256::zero(),
+        };
+
+        let backend = match raw.backend.take().as_deref() {
+            None | Some("local") => local_backend(raw)?,
+            Some("remote") => remote_backend(raw)?,
+            Some(backend) => return Err(eyre!("unknown signer backend: {backend}")),
+        };
+
+        Ok(Self {
+            backend,
+            selection,
+            min_balance,
+        })
     }
 }
+
+fn local_backend(raw: Raw) -> eyre::Result<Backend> {
+    let mut private_keys = raw.private_keys.unwrap_or_default();
+    private_keys.extend(raw.private_key);
+
+    if let Some(path) = raw.private_key_file {
+        private_keys.push(secret::from_file(&path)?);
+    }
+
+    if let Some(name) = raw.private_key_env {
+        private_keys.push(secret::from_env(&name)?);
+    }
+
+    if let Some(path) = raw.keystore {
+        let passphrase = match (raw.keystore_passphrase_file, raw.keystore_passphrase_env) {
+            (Some(path), None) => secret::from_file(&path)?,
+            (None, Some(name)) => secret::from_env(&name)?,
+            _ => eyre::bail!(
+                "exactly one of keystore_passphrase_file or keystore_passphrase_env must be set"
+            ),
+        };
+
+        let wallet = LocalWallet::decrypt_keystore(&path, passphrase)
+            .wrap_err_with(|| format!("failed to decrypt keystore: {}", path.display()))?;
+
+        private_keys.push(hex::encode(wallet.signer().to_bytes()));
+    }
+
+    eyre::ensure!(
+        !private_keys.is_empty(),
+        "one of private_key, private_keys, private_key_file, private_key_env or keystore must be set"
+    );
+
+    Ok(Backend::Local { private_keys })
+}
+
+fn remote_backend(raw: Raw) -> eyre::Result<Backend> {
+    eyre::ensure!(
+        !raw.has_local_key(),
+        "private keys must not be set for the remote signer backend"
+    );
+
+    let url = raw
+        .url
+        .ok_or_else(|| eyre!("url must be set for the remote signer backend"))?;
+    let url = url::Url::parse(&url).wrap_err_with(|| format!("failed to parse URL: {url}"))?;
+
+    let addresses = raw
+        .addresses
+        .unwrap_or_default()
+        .iter()
+        .map(|address| {
+            Address::from_str(address)
+                .wrap_err_with(|| format!("failed to parse signer address: {address}"))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    eyre::ensure!(
+        !addresses.is_empty(),
+        "addresses must be set for the remote signer backend"
+    );
+
+    Ok(Backend::Remote { url, addresses })
+}
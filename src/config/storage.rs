@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+use eyre::eyre;
+
+#[derive(serde::Deserialize)]
+pub(super) struct Raw {
+    backend: String,
+    path: Option<String>,
+}
+
+#[derive(Default)]
+pub enum Config {
+    /// State is lost on restart.
+    #[default]
+    Memory,
+    /// State is kept in the SQLite database file.
+    Sqlite { path: PathBuf },
+}
+
+impl TryFrom<Raw> for Config {
+    type Error = eyre::Error;
+
+    fn try_from(raw: Raw) -> Result<Self, Self::Error> {
+        match raw.backend.as_str() {
+            "memory" => Ok(Self::Memory),
+            "sqlite" => {
+                let path = raw
+                    .path
+                    .ok_or_else(|| eyre!("path is required for sqlite storage"))?;
+
+                Ok(Self::Sqlite { path: path.into() })
+            }
+            backend => Err(eyre!("unknown storage backend: {backend}")),
+        }
+    }
+}
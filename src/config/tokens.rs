@@ -1,6 +1,19 @@
+use std::path::PathBuf;
+
+use super::secret;
+
 #[derive(serde::Deserialize)]
 pub(super) struct Raw {
-    sign_key: String,
+    sign_key: Option<String>,
+    sign_key_file: Option<PathBuf>,
+    sign_key_env: Option<String>,
+}
+
+impl Raw {
+    /// Whether the key is set right in the config file.
+    pub(super) fn has_plaintext_key(&self) -> bool {
+        self.sign_key.is_some()
+    }
 }
 
 #[derive(Default)]
@@ -12,8 +25,15 @@ impl TryFrom<Raw> for Config {
     type Error = eyre::Error;
 
     fn try_from(raw: Raw) -> Result<Self, Self::Error> {
-        Ok(Self {
-            sign_key: raw.sign_key,
-        })
+        let sign_key = match (raw.sign_key, raw.sign_key_file, raw.sign_key_env) {
+            (Some(sign_key), None, None) => sign_key,
+            (None, Some(path), None) => secret::from_file(&path)?,
+            (None, None, Some(name)) => secret::from_env(&name)?,
+            _ => eyre::bail!("exactly one of sign_key, sign_key_file or sign_key_env must be set"),
+        };
+
+        eyre::ensure!(!sign_key.is_empty(), "sign_key must not be empty");
+
+        Ok(Self { sign_key })
     }
 }
@@ -1,6 +1,10 @@
+mod chain;
 mod cli;
 mod config;
+mod metrics;
+mod proto;
 mod service;
+mod storage;
 mod waiter;
 
 #[tokio::main]
@@ -0,0 +1,145 @@
+//! Prometheus metrics of the service, exported over HTTP by [`serve`].
+mod rpc;
+mod server;
+
+pub use rpc::RpcMetricsLayer;
+pub use server::serve;
+
+use ethers_core::{
+    types::{Address, U256},
+    utils::format_ether,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge_vec, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, GaugeVec, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+pub static QUEUE_LENGTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "shuffle_queue_length",
+        "Number of participants waiting in the (token, amount) queue",
+        &["token", "amount"]
+    )
+    .unwrap()
+});
+
+pub static QUEUE_EXPIRED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "shuffle_queue_expired_total",
+        "Number of participants dropped from queues for not polling in time"
+    )
+    .unwrap()
+});
+
+pub static ACTIVE_ROOMS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("shuffle_active_rooms", "Number of rooms in progress").unwrap()
+});
+
+pub static ROOMS_FINISHED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "shuffle_rooms_finished_total",
+        "Number of rooms that have sent the shuffle transaction"
+    )
+    .unwrap()
+});
+
+pub static ROOMS_ABORTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "shuffle_rooms_aborted_total",
+        "Number of rooms closed without the shuffle transaction",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static PHASE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "shuffle_room_phase_duration_seconds",
+        "Time rooms spend in each phase of the shuffle",
+        &["phase"]
+    )
+    .unwrap()
+});
+
+pub static RPC_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "shuffle_rpc_duration_seconds",
+        "Latency of gRPC methods",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static RPC_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "shuffle_rpc_errors_total",
+        "Number of gRPC calls that ended with non OK status",
+        &["method", "code"]
+    )
+    .unwrap()
+});
+
+pub static TRANSFER_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "shuffle_transfer_duration_seconds",
+        "Time it takes to send the shuffle transaction"
+    )
+    .unwrap()
+});
+
+pub static TRANSFERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "shuffle_transfers_total",
+        "Number of shuffle transactions sent, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static TRANSFER_REPLACEMENTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "shuffle_transfer_replacements_total",
+        "Number of stuck shuffle transactions resubmitted with bumped fees"
+    )
+    .unwrap()
+});
+
+pub static TRANSFER_OUTCOMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "shuffle_transfer_outcomes_total",
+        "Number of sent shuffle transactions that became final, by status",
+        &["status"]
+    )
+    .unwrap()
+});
+
+pub static SIGNER_BALANCE: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "shuffle_signer_balance_eth",
+        "Balance of the account the shuffle transactions are sent from, in ETH",
+        &["address"]
+    )
+    .unwrap()
+});
+
+pub static JOINS_REFUSED_LOW_FUNDS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "shuffle_joins_refused_low_funds_total",
+        "Number of joins refused because signer balance doesn't cover active rooms"
+    )
+    .unwrap()
+});
+
+pub fn set_signer_balance(address: Address, balance: U256) {
+    SIGNER_BALANCE
+        .with_label_values(&[&format!("{address:?}")])
+        .set(format_ether(balance).parse().unwrap_or(f64::MAX));
+}
+
+pub fn set_queue_length(token: Address, amount: U256, len: usize) {
+    QUEUE_LENGTH
+        .with_label_values(&[&format!("{token:?}"), &amount.to_string()])
+        .set(len as i64);
+}
@@ -0,0 +1,139 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+
+use tonic::codegen::{http, Body};
+use tower::{Layer, Service};
+
+use super::{RPC_DURATION, RPC_ERRORS};
+
+/// Tower layer that measures latency and counts errors of every gRPC method
+/// served behind it.
+#[derive(Debug, Clone, Default)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<MetricsBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // path of the gRPC request is `/<package>.<Service>/<Method>`
+        let method = request
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        let started_at = Instant::now();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+
+            RPC_DURATION
+                .with_label_values(&[&method])
+                .observe(started_at.elapsed().as_secs_f64());
+
+            let response = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    RPC_ERRORS.with_label_values(&[&method, "transport"]).inc();
+                    return Err(err);
+                }
+            };
+
+            // Errors returned before the response body are sent in headers, the
+            // rest, including errors of the streams, in trailers.
+            let method = if response.headers().contains_key(GRPC_STATUS) {
+                count_error(&method, response.headers());
+                None
+            } else {
+                Some(method)
+            };
+
+            Ok(response.map(|inner| MetricsBody { inner, method }))
+        })
+    }
+}
+
+const GRPC_STATUS: &str = "grpc-status";
+
+/// Response body that counts the error the response ends with in trailers.
+pub struct MetricsBody<B> {
+    inner: B,
+    /// Method the response is of, `None` if the status is already counted.
+    method: Option<String>,
+}
+
+impl<B: Body + Unpin> Body for MetricsBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        let trailers = ready!(Pin::new(&mut self.inner).poll_trailers(cx));
+
+        if let (Some(method), Ok(Some(trailers))) = (self.method.take(), &trailers) {
+            count_error(&method, trailers);
+        }
+
+        Poll::Ready(trailers)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn count_error(method: &str, headers: &http::HeaderMap) {
+    let code = headers
+        .get(GRPC_STATUS)
+        .map(|status| tonic::Code::from_bytes(status.as_bytes()))
+        .filter(|code| *code != tonic::Code::Ok);
+
+    if let Some(code) = code {
+        RPC_ERRORS
+            .with_label_values(&[method, &format!("{code:?}")])
+            .inc();
+    }
+}
@@ -0,0 +1,46 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use eyre::Context;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, TextEncoder};
+
+/// Serves `/metrics` endpoint with metrics in Prometheus text format.
+pub async fn serve(address: SocketAddr) -> eyre::Result<()> {
+    let make_service = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    Server::try_bind(&address)
+        .wrap_err_with(|| format!("failed to bind metrics listener: {address}"))?
+        .serve(make_service)
+        .await
+        .context("metrics server failed")
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("failed to encode metrics: {err}");
+
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
@@ -0,0 +1,13 @@
+//! gRPC services defined in this crate.
+
+pub mod admin {
+    pub mod v1 {
+        tonic::include_proto!("admin.v1");
+    }
+}
+
+pub mod queue {
+    pub mod v1 {
+        tonic::include_proto!("queue.v1");
+    }
+}
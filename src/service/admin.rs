@@ -0,0 +1,258 @@
+use ethers_core::types::{Address, U256};
+use uuid::Uuid;
+
+use crate::{
+    proto::admin::v1::{
+        admin_service_server::AdminService as AdminServiceApi, AbortRoomRequest, AbortRoomResponse,
+        EvictUtxoRequest, EvictUtxoResponse, GetTransferRequest, GetTransferResponse,
+        ListQueuesRequest, ListQueuesResponse, ListRoomsRequest, ListRoomsResponse,
+        PauseJoinsRequest, PauseJoinsResponse, Queue, ResumeJoinsRequest, ResumeJoinsResponse,
+        Room,
+    },
+    storage::u256_to_bytes,
+    waiter::Waiter,
+};
+
+use super::rooms::Rooms;
+
+/// Service for operators to inspect and manage queues and rooms of the [`super::Protocol`].
+pub struct AdminService {
+    waiter: Waiter,
+    rooms: Rooms,
+}
+
+impl AdminService {
+    pub(super) fn new(waiter: Waiter, rooms: Rooms) -> Self {
+        Self { waiter, rooms }
+    }
+}
+
+/// Interceptor of the [`AdminService`] that lets through only requests with
+/// the `authorization: Bearer <token>` metadata, if the token is configured.
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self { token }
+    }
+}
+
+impl tonic::service::Interceptor for AdminAuth {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if !matches!(provided, Some(provided) if tokens_eq(provided, token)) {
+            log::debug!("admin request with invalid token");
+            return Err(tonic::Status::unauthenticated("invalid token"));
+        }
+
+        Ok(request)
+    }
+}
+
+/// Compares tokens in time that doesn't depend on how many bytes match, so the
+/// token can't be guessed byte by byte.
+fn tokens_eq(provided: &str, token: &str) -> bool {
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn parse_token(token: &[u8]) -> Option<Address> {
+    if token.len() != Address::len_bytes() {
+        return None;
+    }
+
+    Some(Address::from_slice(token))
+}
+
+#[tonic::async_trait]
+impl AdminServiceApi for AdminService {
+    async fn list_queues(
+        &self,
+        _request: tonic::Request<ListQueuesRequest>,
+    ) -> Result<tonic::Response<ListQueuesResponse>, tonic::Status> {
+        let queues = self
+            .waiter
+            .queues()
+            .await
+            .into_iter()
+            .map(|((token, amount), size)| Queue {
+                token: token.as_bytes().to_vec(),
+                amount: u256_to_bytes(&amount).to_vec(),
+                size: size as u64,
+            })
+            .collect();
+
+        Ok(tonic::Response::new(ListQueuesResponse { queues }))
+    }
+
+    async fn list_rooms(
+        &self,
+        _request: tonic::Request<ListRoomsRequest>,
+    ) -> Result<tonic::Response<ListRoomsResponse>, tonic::Status> {
+        let rooms = self
+            .rooms
+            .list()
+            .await
+            .into_iter()
+            .map(|room| Room {
+                id: room.id.to_string(),
+                token: room.token.as_bytes().to_vec(),
+                amount: u256_to_bytes(&room.amount).to_vec(),
+                phase: room.phase.as_str().to_string(),
+                participants: room
+                    .participants
+                    .iter()
+                    .map(|id| u256_to_bytes(id).to_vec())
+                    .collect(),
+                connected: room
+                    .connected
+                    .iter()
+                    .map(|id| u256_to_bytes(id).to_vec())
+                    .collect(),
+            })
+            .collect();
+
+        Ok(tonic::Response::new(ListRoomsResponse { rooms }))
+    }
+
+    async fn abort_room(
+        &self,
+        request: tonic::Request<AbortRoomRequest>,
+    ) -> Result<tonic::Response<AbortRoomResponse>, tonic::Status> {
+        let request = request.into_inner();
+
+        let room_id = Uuid::parse_str(&request.room_id)
+            .map_err(|_| tonic::Status::invalid_argument("invalid room id"))?;
+
+        log::info!("admin: abort room {room_id}: {}", request.reason);
+
+        if !self.rooms.abort(&room_id, request.reason).await {
+            return Err(tonic::Status::not_found("room not found"));
+        }
+
+        Ok(tonic::Response::new(AbortRoomResponse {}))
+    }
+
+    async fn evict_utxo(
+        &self,
+        request: tonic::Request<EvictUtxoRequest>,
+    ) -> Result<tonic::Response<EvictUtxoResponse>, tonic::Status> {
+        let utxo_id = U256::from_big_endian(&request.into_inner().utxo_id);
+
+        log::info!("admin: evict utxo {utxo_id}");
+
+        let evicted = self.waiter.evict(utxo_id).await.map_err(|err| {
+            log::error!("failed to evict utxo {utxo_id}: {err}");
+            tonic::Status::internal("internal error")
+        })?;
+
+        if !evicted {
+            return Err(tonic::Status::not_found("utxo is not queued"));
+        }
+
+        Ok(tonic::Response::new(EvictUtxoResponse {}))
+    }
+
+    async fn pause_joins(
+        &self,
+        request: tonic::Request<PauseJoinsRequest>,
+    ) -> Result<tonic::Response<PauseJoinsResponse>, tonic::Status> {
+        let token = parse_token(&request.into_inner().token)
+            .ok_or_else(|| tonic::Status::invalid_argument("invalid token address"))?;
+
+        log::info!("admin: pause joins for token {token:?}");
+        self.waiter.pause(token).await;
+
+        Ok(tonic::Response::new(PauseJoinsResponse {}))
+    }
+
+    async fn resume_joins(
+        &self,
+        request: tonic::Request<ResumeJoinsRequest>,
+    ) -> Result<tonic::Response<ResumeJoinsResponse>, tonic::Status> {
+        let token = parse_token(&request.into_inner().token)
+            .ok_or_else(|| tonic::Status::invalid_argument("invalid token address"))?;
+
+        log::info!("admin: resume joins for token {token:?}");
+        self.waiter.resume(token).await;
+
+        Ok(tonic::Response::new(ResumeJoinsResponse {}))
+    }
+
+    async fn get_transfer(
+        &self,
+        request: tonic::Request<GetTransferRequest>,
+    ) -> Result<tonic::Response<GetTransferResponse>, tonic::Status> {
+        let room_id = Uuid::parse_str(&request.into_inner().room_id)
+            .map_err(|_| tonic::Status::invalid_argument("invalid room id"))?;
+
+        let transfer = self
+            .rooms
+            .transfer(&room_id)
+            .await
+            .map_err(|err| {
+                log::error!("failed to get transfer of room {room_id}: {err}");
+                tonic::Status::internal("internal error")
+            })?
+            .ok_or_else(|| tonic::Status::not_found("transfer not found"))?;
+
+        Ok(tonic::Response::new(GetTransferResponse {
+            tx_hash: transfer.tx_hash.as_bytes().to_vec(),
+            status: transfer.status.as_str().to_string(),
+            block_number: transfer.block_number.unwrap_or_default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::service::Interceptor;
+
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.parse().unwrap());
+        }
+
+        request
+    }
+
+    #[test]
+    fn checks_admin_token() {
+        let mut auth = AdminAuth::new(Some("secret".to_string()));
+
+        assert!(auth.call(request(Some("Bearer secret"))).is_ok());
+        assert!(auth.call(request(Some("Bearer secreT"))).is_err());
+        assert!(auth.call(request(Some("Bearer secret2"))).is_err());
+        assert!(auth.call(request(Some("secret"))).is_err());
+        assert!(auth.call(request(None)).is_err());
+    }
+
+    #[test]
+    fn lets_requests_through_without_admin_token() {
+        let mut auth = AdminAuth::new(None);
+
+        assert!(auth.call(request(None)).is_ok());
+    }
+}
@@ -1,45 +1,198 @@
 use std::{
+    collections::BTreeSet,
     ops::Deref,
     sync::Arc,
     time::{Duration, SystemTime},
 };
 
-use ethers_core::types::{Address, RecoveryMessage, Signature, U256};
+use ethers_core::{
+    abi::{self, Token},
+    types::{transaction::eip712::EIP712Domain, Address, RecoveryMessage, Signature, H256, U256},
+    utils::keccak256,
+};
 use eyre::{eyre, Context, ContextCompat};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use open_fastrlp::Decodable;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 const U256_BYTES: usize = 32;
 const TIMESTAMP_BYTES: usize = 8;
 const MESSAGE_LEN: usize = U256_BYTES + TIMESTAMP_BYTES;
 
-pub fn verify_join_signature(
-    utxo_id: &U256,
-    timestamp: u64,
-    signature: Vec<u8>,
-    owner: impl Into<Address>,
-) -> Result<(), JoinSignatureError> {
-    let mut message = vec![0u8; MESSAGE_LEN];
+/// Request metadata key to choose the scheme of the join signature.
+const SCHEME_METADATA_KEY: &str = "x-signature-scheme";
 
-    utxo_id.to_big_endian(&mut message[0..U256_BYTES]);
-    message[U256_BYTES..MESSAGE_LEN].copy_from_slice(&timestamp.to_be_bytes());
+const EIP712_DOMAIN_NAME: &str = "CoinShuffle";
+const EIP712_DOMAIN_VERSION: &str = "1";
+const JOIN_TYPE: &str =
+    "JoinShuffleRoom(uint256 utxoId,address token,uint256 amount,uint64 timestamp)";
+const LEAVE_TYPE: &str = "LeaveShuffleQueue(uint256 utxoId,uint64 timestamp)";
 
-    let signature = Signature::decode(&mut signature.deref())
-        .map_err(|err| JoinSignatureError::InvalidSignature(eyre!("failed to decode: {err}")))?;
+/// Format of the message the UTXO owner signs to join the shuffle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinSignatureScheme {
+    /// Raw concatenation of utxo id and timestamp, signed as EIP-191 personal message.
+    Legacy,
+    /// EIP-712 typed data bound to the chain, contract, token and amount.
+    Eip712,
+}
 
-    let now = SystemTime::now();
-    let signature_creation_time = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp);
+impl JoinSignatureScheme {
+    /// Reads the scheme from the request metadata, [`Self::Legacy`] if it's absent.
+    pub fn from_request<T>(req: &tonic::Request<T>) -> eyre::Result<Self> {
+        let Some(scheme) = req.metadata().get(SCHEME_METADATA_KEY) else {
+            return Ok(Self::Legacy);
+        };
 
-    if now.duration_since(signature_creation_time).is_err() {
-        return Err(JoinSignatureError::InvalidTimestamp(timestamp));
+        match scheme.to_str()? {
+            "legacy" => Ok(Self::Legacy),
+            "eip712" => Ok(Self::Eip712),
+            scheme => Err(eyre!("unknown signature scheme: {scheme}")),
+        }
     }
+}
 
-    let msg = RecoveryMessage::Data(message);
+/// Join message signed by the UTXO owner.
+pub struct JoinMessage {
+    pub utxo_id: U256,
+    pub token: Address,
+    pub amount: U256,
+    pub timestamp: u64,
+}
+
+impl JoinMessage {
+    fn legacy(&self) -> RecoveryMessage {
+        let mut message = vec![0u8; MESSAGE_LEN];
 
-    signature
-        .verify(msg, owner)
-        .map_err(|err| JoinSignatureError::InvalidSignature(eyre!("invalid signature: {err}")))
+        self.utxo_id.to_big_endian(&mut message[0..U256_BYTES]);
+        message[U256_BYTES..MESSAGE_LEN].copy_from_slice(&self.timestamp.to_be_bytes());
+
+        RecoveryMessage::Data(message)
+    }
+
+    fn eip712(&self, domain_separator: &[u8; 32]) -> RecoveryMessage {
+        let struct_hash = keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(JOIN_TYPE).to_vec()),
+            Token::Uint(self.utxo_id),
+            Token::Address(self.token),
+            Token::Uint(self.amount),
+            Token::Uint(self.timestamp.into()),
+        ]));
+
+        typed_data_hash(domain_separator, &struct_hash)
+    }
+}
+
+/// EIP-712 hash of the typed data to sign.
+fn typed_data_hash(domain_separator: &[u8; 32], struct_hash: &[u8; 32]) -> RecoveryMessage {
+    let mut digest = Vec::with_capacity(2 + 2 * U256_BYTES);
+    digest.extend_from_slice(&[0x19, 0x01]);
+    digest.extend_from_slice(domain_separator);
+    digest.extend_from_slice(struct_hash);
+
+    RecoveryMessage::Hash(H256(keccak256(digest)))
+}
+
+/// Verifies signatures of join requests in any of the [`JoinSignatureScheme`]s.
+#[derive(Clone)]
+pub struct JoinSignatureVerifier {
+    domain_separator: [u8; 32],
+    legacy_enabled: bool,
+    max_age: Duration,
+}
+
+impl JoinSignatureVerifier {
+    /// Creates verifier for the typed data bound to the UTXO contract on the chain.
+    pub fn new(
+        chain_id: u64,
+        utxo_contract: Address,
+        legacy_enabled: bool,
+        max_age: Duration,
+    ) -> Self {
+        let domain = EIP712Domain {
+            name: Some(EIP712_DOMAIN_NAME.to_string()),
+            version: Some(EIP712_DOMAIN_VERSION.to_string()),
+            chain_id: Some(chain_id.into()),
+            verifying_contract: Some(utxo_contract),
+            salt: None,
+        };
+
+        Self {
+            domain_separator: domain.separator(),
+            legacy_enabled,
+            max_age,
+        }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    pub fn verify(
+        &self,
+        scheme: JoinSignatureScheme,
+        message: &JoinMessage,
+        signature: Vec<u8>,
+        owner: impl Into<Address>,
+    ) -> Result<(), JoinSignatureError> {
+        let msg = match scheme {
+            JoinSignatureScheme::Legacy if !self.legacy_enabled => {
+                return Err(JoinSignatureError::SchemeDisabled(scheme));
+            }
+            JoinSignatureScheme::Legacy => message.legacy(),
+            JoinSignatureScheme::Eip712 => message.eip712(&self.domain_separator),
+        };
+
+        self.verify_message(msg, message.timestamp, signature, owner)
+    }
+
+    /// Verifies the owner's request to leave the queue, which is always signed
+    /// as EIP-712 typed data, so it can't be confused with the join message.
+    pub fn verify_leave(
+        &self,
+        utxo_id: U256,
+        timestamp: u64,
+        signature: Vec<u8>,
+        owner: impl Into<Address>,
+    ) -> Result<(), JoinSignatureError> {
+        let struct_hash = keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(LEAVE_TYPE).to_vec()),
+            Token::Uint(utxo_id),
+            Token::Uint(timestamp.into()),
+        ]));
+
+        let msg = typed_data_hash(&self.domain_separator, &struct_hash);
+
+        self.verify_message(msg, timestamp, signature, owner)
+    }
+
+    fn verify_message(
+        &self,
+        msg: RecoveryMessage,
+        timestamp: u64,
+        signature: Vec<u8>,
+        owner: impl Into<Address>,
+    ) -> Result<(), JoinSignatureError> {
+        let signature = Signature::decode(&mut signature.deref()).map_err(|err| {
+            JoinSignatureError::InvalidSignature(eyre!("failed to decode: {err}"))
+        })?;
+
+        let now = SystemTime::now();
+        let signature_creation_time = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp);
+
+        let Ok(age) = now.duration_since(signature_creation_time) else {
+            return Err(JoinSignatureError::InvalidTimestamp(timestamp));
+        };
+
+        if age > self.max_age {
+            return Err(JoinSignatureError::Expired(timestamp));
+        }
+
+        signature
+            .verify(msg, owner)
+            .map_err(|err| JoinSignatureError::InvalidSignature(eyre!("invalid signature: {err}")))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -48,6 +201,81 @@ pub enum JoinSignatureError {
     InvalidSignature(eyre::Error),
     #[error("Invalid timestamp: {0}")]
     InvalidTimestamp(u64),
+    #[error("Signature is expired, timestamp: {0}")]
+    Expired(u64),
+    #[error("Signature is already used, utxo id: {0}, timestamp: {1}")]
+    Replayed(U256, u64),
+    #[error("Signature scheme is disabled: {0:?}")]
+    SchemeDisabled(JoinSignatureScheme),
+    #[error("Too many unexpired signatures are used")]
+    Exhausted,
+}
+
+/// Bounded set of (utxo_id, timestamp) pairs of join signatures that were
+/// already accepted, so the same signature can't be used twice.
+///
+/// Pairs older than the maximum signature age are dropped, as such signatures
+/// are rejected as expired anyway. Unexpired pairs are never dropped, so when
+/// there are `capacity` of them, new signatures are refused until some expire.
+#[derive(Clone)]
+pub struct UsedSignatures {
+    /// Pairs as (timestamp, utxo_id), so they are ordered by age and expired
+    /// ones are pruned from the front.
+    used: Arc<Mutex<BTreeSet<(u64, U256)>>>,
+    capacity: usize,
+    max_age: Duration,
+}
+
+impl UsedSignatures {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            used: Arc::new(Mutex::new(BTreeSet::new())),
+            capacity,
+            max_age,
+        }
+    }
+
+    /// Marks signature as used. Returns error if it was used before or there's
+    /// no room for it until older signatures expire.
+    pub async fn use_signature(
+        &self,
+        utxo_id: U256,
+        timestamp: u64,
+    ) -> Result<(), JoinSignatureError> {
+        let mut used = self.used.lock().await;
+
+        let expired_before = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(self.max_age)
+            .as_secs();
+
+        // Only the expired prefix is visited.
+        while let Some(oldest) = used.iter().next().cloned() {
+            if oldest.0 >= expired_before {
+                break;
+            }
+            used.remove(&oldest);
+        }
+
+        if used.contains(&(timestamp, utxo_id)) {
+            return Err(JoinSignatureError::Replayed(utxo_id, timestamp));
+        }
+
+        // Dropping unexpired signatures would let them be replayed.
+        if used.len() >= self.capacity {
+            return Err(JoinSignatureError::Exhausted);
+        }
+
+        used.insert((timestamp, utxo_id));
+
+        Ok(())
+    }
+
+    /// Forgets the signature, so it can be used again.
+    pub async fn forget(&self, utxo_id: U256, timestamp: u64) {
+        self.used.lock().await.remove(&(timestamp, utxo_id));
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -166,3 +394,152 @@ impl TokensGenerator {
         Ok(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use open_fastrlp::Encodable;
+
+    use super::*;
+
+    /// First of the well-known development accounts.
+    const OWNER: &str = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+    const CHAIN_ID: u64 = 5;
+    const UTXO_CONTRACT: &str = "0x4C0d116d9d028E60904DCA468b9Fa7537Ef8Cd5f";
+
+    const UTXO_ID: u64 = 42;
+    const TOKEN: &str = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+    const AMOUNT: u64 = 1_000_000_000_000_000_000;
+    const TIMESTAMP: u64 = 1_700_000_000;
+
+    /// `eth_signTypedData_v4` of the `JoinShuffleRoom` message above by the
+    /// owner's key.
+    const JOIN_SIGNATURE: &str = "7818cf7256179bec3874397fc728dcc2ecc0415ff33d4e2cf36537043ca52c643ca5710818ad1677624e94a9ee2553959e5020c2372439379ed7e81d0fe4f50b1c";
+    /// `eth_signTypedData_v4` of the `LeaveShuffleQueue` message above by the
+    /// owner's key.
+    const LEAVE_SIGNATURE: &str = "550b37d028aebd309156aa209d1982eb48842d6737ee89e3ba4a2add39b4c2be30c20f3f1b27c213068610c9f608da911c04bd90ee4369a765d4b6acf7efdbdd1c";
+
+    fn verifier() -> JoinSignatureVerifier {
+        let contract = Address::from_str(UTXO_CONTRACT).unwrap();
+
+        JoinSignatureVerifier::new(CHAIN_ID, contract, false, Duration::MAX)
+    }
+
+    fn join_message() -> JoinMessage {
+        JoinMessage {
+            utxo_id: UTXO_ID.into(),
+            token: Address::from_str(TOKEN).unwrap(),
+            amount: AMOUNT.into(),
+            timestamp: TIMESTAMP,
+        }
+    }
+
+    /// Encodes the signature the way clients send it in the requests.
+    fn encode_signature(hex: &str) -> Vec<u8> {
+        let signature = Signature::from_str(hex).unwrap();
+
+        let mut encoded = Vec::new();
+        signature.encode(&mut encoded);
+        encoded
+    }
+
+    fn owner() -> Address {
+        Address::from_str(OWNER).unwrap()
+    }
+
+    #[test]
+    fn verifies_typed_data_join_signature() {
+        verifier()
+            .verify(
+                JoinSignatureScheme::Eip712,
+                &join_message(),
+                encode_signature(JOIN_SIGNATURE),
+                owner(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_join_signature_of_other_amount() {
+        let message = JoinMessage {
+            amount: (AMOUNT + 1).into(),
+            ..join_message()
+        };
+
+        let result = verifier().verify(
+            JoinSignatureScheme::Eip712,
+            &message,
+            encode_signature(JOIN_SIGNATURE),
+            owner(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(JoinSignatureError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_typed_data_leave_signature() {
+        verifier()
+            .verify_leave(
+                UTXO_ID.into(),
+                TIMESTAMP,
+                encode_signature(LEAVE_SIGNATURE),
+                owner(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_join_signature_as_leave() {
+        let result = verifier().verify_leave(
+            UTXO_ID.into(),
+            TIMESTAMP,
+            encode_signature(JOIN_SIGNATURE),
+            owner(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(JoinSignatureError::InvalidSignature(_))
+        ));
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_signature() {
+        let used = UsedSignatures::new(10, Duration::from_secs(60));
+        let timestamp = now();
+
+        used.use_signature(1.into(), timestamp).await.unwrap();
+        let result = used.use_signature(1.into(), timestamp).await;
+
+        assert!(matches!(result, Err(JoinSignatureError::Replayed(..))));
+    }
+
+    #[tokio::test]
+    async fn refuses_signatures_over_capacity_until_older_expire() {
+        let used = UsedSignatures::new(2, Duration::from_secs(60));
+        let timestamp = now();
+
+        used.use_signature(1.into(), timestamp - 120).await.unwrap();
+        used.use_signature(2.into(), timestamp).await.unwrap();
+        // The first one has expired, so there's room for another one.
+        used.use_signature(3.into(), timestamp).await.unwrap();
+
+        let result = used.use_signature(4.into(), timestamp).await;
+        assert!(matches!(result, Err(JoinSignatureError::Exhausted)));
+
+        // Unexpired signatures are still remembered.
+        let result = used.use_signature(2.into(), timestamp).await;
+        assert!(matches!(result, Err(JoinSignatureError::Replayed(..))));
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use ethers_core::types::U256;
+use tokio::time::sleep;
+
+use crate::{chain::UtxoContract, metrics};
+
+/// How often balances of the service accounts are polled.
+const BALANCE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Balance of the service accounts the shuffle transactions are paid from,
+/// which new participants are admitted by.
+#[derive(Clone)]
+pub struct Funds {
+    contract: UtxoContract,
+    /// Total balance of the accounts in rotation by the last poll, `None` until
+    /// the first poll succeeds.
+    available: Arc<Mutex<Option<U256>>>,
+    /// Whether the last check found the balance too low, so only the changes
+    /// are logged, while every refused join is counted by the metric.
+    low: Arc<AtomicBool>,
+}
+
+impl Funds {
+    pub fn new(contract: UtxoContract) -> Self {
+        Self {
+            contract,
+            available: Arc::new(Mutex::new(None)),
+            low: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Polls balances of the service accounts and exports them as metrics.
+    pub async fn poll(self) {
+        loop {
+            match self.contract.signer_balances().await {
+                Ok(balances) => {
+                    for (address, balance) in balances.accounts {
+                        metrics::set_signer_balance(address, balance);
+                    }
+
+                    *self.available.lock().unwrap() = Some(balances.available);
+                }
+                Err(err) => log::warn!("failed to poll signer balances: {err}"),
+            }
+
+            sleep(BALANCE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Checks that the balance covers shuffle transactions of the `active_rooms`
+    /// and the room the new participant would join, so rooms don't fail when
+    /// it's too late to tell participants.
+    pub fn is_enough(&self, active_rooms: usize) -> bool {
+        // Joins are not blocked on the node being unreachable at startup.
+        let Some(available) = *self.available.lock().unwrap() else {
+            return true;
+        };
+
+        let required = self
+            .contract
+            .transfer_cost()
+            .saturating_mul(U256::from(active_rooms + 1));
+
+        let is_enough = available >= required;
+        let was_low = self.low.swap(!is_enough, Ordering::Relaxed);
+
+        if !is_enough && !was_low {
+            log::warn!(
+                "signer balance {available} doesn't cover {required} for {active_rooms} active rooms, joins are refused"
+            );
+        } else if is_enough && was_low {
+            log::info!("signer balance {available} covers {required}, joins are accepted again");
+        }
+
+        is_enough
+    }
+}
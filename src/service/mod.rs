@@ -1,7 +1,11 @@
+mod admin;
 mod auth;
+mod funds;
+mod queue;
 mod room;
+mod rooms;
+mod transfer;
 
-use coin_shuffle_contracts_bindings::utxo::{self, Contract};
 use coin_shuffle_core::service::Service;
 use coin_shuffle_protos::v1::{
     shuffle_service_server::ShuffleService, ConnectShuffleRoomRequest, IsReadyForShuffleRequest,
@@ -10,56 +14,131 @@ use coin_shuffle_protos::v1::{
 };
 use ethers_core::abi::ethereum_types::Signature;
 use ethers_core::types::U256;
-use ethers_middleware::SignerMiddleware;
-use ethers_providers::{Http, Provider};
-use ethers_signers::LocalWallet;
+use eyre::Context;
 use rsa::{BigUint, RsaPublicKey};
-use std::collections::hash_map::Entry::Vacant;
-use std::time::Duration;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Sender as StreamSender;
-use tokio::sync::Mutex;
-use tokio::time::{interval_at, Instant};
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{channel, Sender as StreamSender},
+    oneshot,
+};
 use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 
-use crate::waiter::Waiter;
+use crate::{
+    chain::UtxoContract,
+    metrics,
+    storage::Storage,
+    waiter::{Participant, Waiter, WaiterOptions},
+};
 
+pub use self::admin::{AdminAuth, AdminService};
+pub use self::auth::JoinSignatureVerifier;
+pub use self::queue::QueueService;
+pub use self::room::RoomDeadlines;
 use self::{
-    auth::{verify_join_signature, TokensGenerator},
-    room::{RoomConnectionManager, RoomEvents},
+    auth::{JoinMessage, JoinSignatureError, JoinSignatureScheme, TokensGenerator, UsedSignatures},
+    funds::Funds,
+    room::RoomEvents,
+    rooms::Rooms,
 };
 
 // TODO: Separate requests data parsing level
 
 pub struct Protocol {
     service: Service,
-    utxo_contract: utxo::Connector<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    utxo_contract: UtxoContract,
     tokens_generator: TokensGenerator,
 
-    shuffle_round_deadline: Duration,
+    join_verifier: JoinSignatureVerifier,
+    used_signatures: UsedSignatures,
+    used_leave_signatures: UsedSignatures,
 
     waiter: Waiter,
-    rooms: Arc<Mutex<HashMap<Uuid, StreamSender<RoomEvents>>>>,
+    rooms: Rooms,
+    funds: Funds,
 }
 
 impl Protocol {
     pub fn new(
-        contract: utxo::Connector<SignerMiddleware<Provider<Http>, LocalWallet>>,
+        contract: UtxoContract,
         token_key: String,
-        shuffle_round_deadline: Duration,
-        min_room_size: usize,
+        deadlines: RoomDeadlines,
+        waiter_options: WaiterOptions,
+        storage: Arc<dyn Storage>,
+        join_verifier: JoinSignatureVerifier,
+        used_signatures_capacity: usize,
     ) -> Self {
+        let service = Service::new();
+        let tokens_generator = TokensGenerator::new(token_key);
+        let (waiter, filled_queues) = Waiter::new(waiter_options, storage.clone());
+
+        let rooms = Rooms::new(
+            service.clone(),
+            waiter.clone(),
+            tokens_generator.clone(),
+            contract.clone(),
+            deadlines,
+            storage,
+        );
+        tokio::spawn(rooms.clone().listen(filled_queues));
+        tokio::spawn(waiter.clone().sweep_expired());
+        tokio::spawn(waiter.clone().form_timed_out());
+
+        let funds = Funds::new(contract.clone());
+        tokio::spawn(funds.clone().poll());
+
         Self {
-            shuffle_round_deadline,
-            waiter: Waiter::new(min_room_size),
-            service: Service::new(),
+            waiter,
+            rooms,
+            funds,
+            service,
             utxo_contract: contract,
-            tokens_generator: TokensGenerator::new(token_key),
-            rooms: Arc::new(Mutex::new(HashMap::new())),
+            tokens_generator,
+            used_signatures: UsedSignatures::new(used_signatures_capacity, join_verifier.max_age()),
+            used_leave_signatures: UsedSignatures::new(
+                used_signatures_capacity,
+                join_verifier.max_age(),
+            ),
+            join_verifier,
         }
     }
+
+    /// Creates service for operators that manages queues and rooms of this protocol.
+    pub fn admin_service(&self) -> AdminService {
+        AdminService::new(self.waiter.clone(), self.rooms.clone())
+    }
+
+    /// Creates service for participants to manage their place in the queues.
+    pub fn queue_service(&self) -> QueueService {
+        QueueService::new(
+            self.service.clone(),
+            self.waiter.clone(),
+            self.rooms.clone(),
+            self.tokens_generator.clone(),
+            self.utxo_contract.clone(),
+            self.join_verifier.clone(),
+            self.used_leave_signatures.clone(),
+        )
+    }
+
+    /// Aborts rooms interrupted by restart and restores queues from the storage
+    /// along with the participants of those rooms.
+    pub async fn restore(&self) -> eyre::Result<()> {
+        self.rooms
+            .abort_interrupted()
+            .await
+            .context("failed to abort interrupted rooms")?;
+
+        self.waiter
+            .restore()
+            .await
+            .context("failed to restore queues")?;
+
+        self.rooms
+            .resume_transfers()
+            .await
+            .context("failed to resume transfers tracking")
+    }
 }
 
 #[tonic::async_trait]
@@ -68,6 +147,18 @@ impl ShuffleService for Protocol {
         &self,
         request: tonic::Request<JoinShuffleRoomRequest>,
     ) -> Result<tonic::Response<JoinShuffleRoomResponse>, tonic::Status> {
+        let scheme = JoinSignatureScheme::from_request(&request).map_err(|err| {
+            log::debug!("failed to parse signature scheme: {err}");
+            tonic::Status::invalid_argument("invalid signature scheme")
+        })?;
+
+        if !self.funds.is_enough(self.rooms.count().await) {
+            metrics::JOINS_REFUSED_LOW_FUNDS.inc();
+            return Err(tonic::Status::unavailable(
+                "service is low on funds for shuffle transactions, try again later",
+            ));
+        }
+
         let request = request.into_inner();
 
         let utxo_id = U256::from_big_endian(&request.utxo_id);
@@ -85,26 +176,68 @@ impl ShuffleService for Protocol {
                 tonic::Status::invalid_argument("no utxo with such id")
             })?;
 
-        verify_join_signature(&utxo.id, request.timestamp, request.signature, utxo.owner).map_err(
-            |err| {
-                log::debug!("failed to verify join signature: {err}");
-                tonic::Status::invalid_argument("invalid signature or timestamp")
-            },
-        )?;
+        if utxo.is_spent {
+            log::debug!("utxo with id {utxo_id} is already spent");
+            return Err(tonic::Status::failed_precondition("utxo is already spent"));
+        }
 
-        if let Some(participants) = self
-            .waiter
-            .add_participant(utxo.token, utxo.amount, utxo.id)
+        if self.waiter.is_paused(utxo.token).await {
+            log::debug!("joins are paused for token {:?}", utxo.token);
+            return Err(tonic::Status::unavailable(
+                "joins for this token are paused",
+            ));
+        }
+
+        let message = JoinMessage {
+            utxo_id: utxo.id,
+            token: utxo.token,
+            amount: utxo.amount,
+            timestamp: request.timestamp,
+        };
+
+        self.join_verifier
+            .verify(scheme, &message, request.signature, utxo.owner)
+            .map_err(join_signature_status)?;
+
+        self.used_signatures
+            .use_signature(utxo.id, request.timestamp)
             .await
-        {
-            let room = self
-                .service
-                .create_room(utxo.token, utxo.amount, participants)
-                .await;
+            .map_err(join_signature_status)?;
 
-            log::debug!("room created: {room:?}");
+        let added = self
+            .waiter
+            .add_participant(
+                utxo.token,
+                utxo.amount,
+                Participant {
+                    utxo_id: utxo.id,
+                    owner: utxo.owner,
+                },
+            )
+            .await;
+
+        let added = match added {
+            Ok(added) => added,
+            Err(err) => {
+                log::error!("failed to add participant to the queue: {err}");
+
+                // Signature is not spent on the join that failed, so the same
+                // request can be retried.
+                self.used_signatures
+                    .forget(utxo.id, request.timestamp)
+                    .await;
+
+                return Err(tonic::Status::internal("internal error"));
+            }
         };
 
+        // Owner of the UTXO that has already joined gets the access token again
+        // on a freshly signed request, while the same request is rejected above
+        // as replayed.
+        if !added {
+            log::debug!("utxo {} is already queued or in a room", utxo.id);
+        }
+
         Ok(tonic::Response::new(JoinShuffleRoomResponse {
             room_access_token: self
                 .tokens_generator
@@ -130,6 +263,16 @@ impl ShuffleService for Protocol {
 
         let participant = self.service.get_participant(&claims.utxo_id).await;
 
+        if participant.is_none()
+            && !self.waiter.touch(claims.utxo_id).await
+            && !self.waiter.is_seated(claims.utxo_id).await
+        {
+            log::debug!("participant is not queued, utxo_id: {}", claims.utxo_id);
+            return Err(tonic::Status::not_found(
+                "participant is not queued, join again",
+            ));
+        }
+
         let new_token = self
             .tokens_generator
             .generate_shuffle_token(claims.token, claims.amount, claims.utxo_id)
@@ -159,21 +302,18 @@ impl ShuffleService for Protocol {
                 tonic::Status::unauthenticated("invalid token")
             })?;
 
-        let participant = self
-            .service
-            .get_participant(&claims.utxo_id)
-            .await
-            .ok_or_else(|| {
-                log::debug!("participant is absent, utxo_id: {}", claims.utxo_id);
-                tonic::Status::not_found("participant is absent")
-            })?;
+        let Some(participant) = self.service.get_participant(&claims.utxo_id).await else {
+            if let Some(reason) = self.rooms.participant_aborted_reason(&claims.utxo_id).await {
+                return Err(tonic::Status::aborted(reason));
+            }
+
+            log::debug!("participant is absent, utxo_id: {}", claims.utxo_id);
+            return Err(tonic::Status::not_found("participant is absent"));
+        };
 
         let room_id = participant.room_id;
 
-        let room_stream = self.get_room_stream(room_id).await.ok_or_else(|| {
-            log::error!("failed to find the room with id: {}", room_id);
-            tonic::Status::internal("internal error")
-        })?;
+        let room_stream = self.get_room_stream(&room_id).await?;
 
         let (event_sender, event_receiver) = channel(10);
 
@@ -221,10 +361,7 @@ impl ShuffleService for Protocol {
                 tonic::Status::unauthenticated("invalid token")
             })?;
 
-        let room_stream = self.get_room_stream(claims.room_id).await.ok_or_else(|| {
-            log::error!("failed to find the room with id: {}", claims.room_id);
-            tonic::Status::internal("internal error")
-        })?;
+        let room_stream = self.get_room_stream(&claims.room_id).await?;
 
         room_stream
             .send(RoomEvents::ShuffleRound((
@@ -256,16 +393,23 @@ impl ShuffleService for Protocol {
                 tonic::Status::unauthenticated("invalid token")
             })?;
 
-        let room_stream = self.get_room_stream(claims.room_id).await.ok_or_else(|| {
-            log::error!("failed to find the room with id: {}", claims.room_id);
-            tonic::Status::internal("internal error")
-        })?;
+        let signature = request.into_inner().signature;
+        if signature.len() != Signature::len_bytes() {
+            return Err(tonic::Status::invalid_argument(format!(
+                "signature must be {} bytes long",
+                Signature::len_bytes()
+            )));
+        }
+
+        let room_stream = self.get_room_stream(&claims.room_id).await?;
+        let (reply, verified) = oneshot::channel();
 
         room_stream
-            .send(RoomEvents::SignedOutput((
-                claims.utxo_id,
-                Signature::from_slice(request.into_inner().signature.as_slice()),
-            )))
+            .send(RoomEvents::SignedOutput {
+                utxo_id: claims.utxo_id,
+                signature: Signature::from_slice(&signature),
+                reply,
+            })
             .await
             .map_err(|err| {
                 log::error!(
@@ -276,37 +420,49 @@ impl ShuffleService for Protocol {
                 tonic::Status::internal("internal error")
             })?;
 
+        // Room drops the reply only when it's closed before the signature is handled.
+        verified
+            .await
+            .map_err(|_| tonic::Status::aborted("room is closed"))??;
+
         Ok(tonic::Response::new(SignShuffleTxResponse {}))
     }
 }
 
 impl Protocol {
-    async fn get_room_stream(&self, room_id: Uuid) -> Option<StreamSender<RoomEvents>> {
-        let mut rooms = self.rooms.lock().await;
-
-        if let Vacant(e) = rooms.entry(room_id) {
-            let (internal_events_sender, internal_events_receiver) = channel(10);
-            let mut room = RoomConnectionManager::new(
-                internal_events_receiver,
-                self.service.get_room(&room_id).await?, // TODO: check if this behaviour is valid
-                self.service.clone(),
-                self.tokens_generator.clone(),
-                self.utxo_contract.clone(),
-            );
-            room.set_deadline(interval_at(
-                Instant::now() + self.shuffle_round_deadline,
-                self.shuffle_round_deadline,
-            ));
+    async fn get_room_stream(
+        &self,
+        room_id: &Uuid,
+    ) -> Result<StreamSender<RoomEvents>, tonic::Status> {
+        if let Some(room_stream) = self.rooms.get(room_id).await {
+            return Ok(room_stream);
+        }
 
-            tokio::spawn(async move {
-                room.run().await;
-            });
+        if let Some(reason) = self.rooms.aborted_reason(room_id).await {
+            return Err(tonic::Status::aborted(reason));
+        }
 
-            e.insert(internal_events_sender.clone());
+        log::error!("failed to find the room with id: {}", room_id);
+        Err(tonic::Status::internal("internal error"))
+    }
+}
 
-            return Some(internal_events_sender);
-        }
+fn join_signature_status(err: JoinSignatureError) -> tonic::Status {
+    log::debug!("failed to verify join signature: {err}");
 
-        rooms.get(&room_id).cloned()
+    match err {
+        JoinSignatureError::Expired(_) => {
+            tonic::Status::failed_precondition("join signature is expired")
+        }
+        JoinSignatureError::Replayed(..) => {
+            tonic::Status::already_exists("join signature is already used")
+        }
+        JoinSignatureError::SchemeDisabled(_) => {
+            tonic::Status::invalid_argument("signature scheme is disabled")
+        }
+        JoinSignatureError::Exhausted => {
+            tonic::Status::resource_exhausted("too many recent joins, try again later")
+        }
+        _ => tonic::Status::invalid_argument("invalid signature or timestamp"),
     }
 }
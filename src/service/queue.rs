@@ -0,0 +1,270 @@
+use coin_shuffle_core::service::Service;
+use ethers_core::types::U256;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    chain::UtxoContract,
+    proto::queue::v1::{
+        queue_service_server::QueueService as QueueServiceApi, GetQueueStatusRequest,
+        GetQueueStatusResponse, GetTransferStatusRequest, GetTransferStatusResponse,
+        LeaveQueueRequest, LeaveQueueResponse, RoomReadyEvent, WatchRoomReadyRequest,
+    },
+    waiter::Waiter,
+};
+
+use super::{
+    auth::{JoinSignatureVerifier, TokensGenerator, UsedSignatures},
+    join_signature_status,
+    rooms::Rooms,
+};
+
+/// Service for participants to manage their place in the queues of the [`super::Protocol`].
+pub struct QueueService {
+    service: Service,
+    waiter: Waiter,
+    rooms: Rooms,
+    tokens_generator: TokensGenerator,
+    utxo_contract: UtxoContract,
+    verifier: JoinSignatureVerifier,
+    used_signatures: UsedSignatures,
+}
+
+impl QueueService {
+    pub(super) fn new(
+        service: Service,
+        waiter: Waiter,
+        rooms: Rooms,
+        tokens_generator: TokensGenerator,
+        utxo_contract: UtxoContract,
+        verifier: JoinSignatureVerifier,
+        used_signatures: UsedSignatures,
+    ) -> Self {
+        Self {
+            service,
+            waiter,
+            rooms,
+            tokens_generator,
+            utxo_contract,
+            verifier,
+            used_signatures,
+        }
+    }
+
+    /// Returns UTXO id the leave request is authenticated for, either by the
+    /// shuffle access token or by the owner signature.
+    async fn authenticate(
+        &self,
+        request: &tonic::Request<LeaveQueueRequest>,
+    ) -> Result<U256, tonic::Status> {
+        if request.metadata().contains_key("authorization") {
+            let claims = self
+                .tokens_generator
+                .decode_shuffle_token(request)
+                .map_err(|err| {
+                    log::debug!("failed to decode token: {err}");
+                    tonic::Status::unauthenticated("invalid token")
+                })?;
+
+            return Ok(claims.utxo_id);
+        }
+
+        let request = request.get_ref();
+        let utxo_id = U256::from_big_endian(&request.utxo_id);
+
+        let utxo = self
+            .utxo_contract
+            .get_utxo_by_id(utxo_id)
+            .await
+            .map_err(|err| {
+                log::error!("failed to get utxo from contract: {err}");
+                tonic::Status::internal("internal error")
+            })?
+            .ok_or_else(|| {
+                log::debug!("utxo with id {utxo_id} not found");
+                tonic::Status::invalid_argument("no utxo with such id")
+            })?;
+
+        self.verifier
+            .verify_leave(
+                utxo.id,
+                request.timestamp,
+                request.signature.clone(),
+                utxo.owner,
+            )
+            .map_err(join_signature_status)?;
+
+        self.used_signatures
+            .use_signature(utxo.id, request.timestamp)
+            .await
+            .map_err(join_signature_status)?;
+
+        Ok(utxo.id)
+    }
+}
+
+#[tonic::async_trait]
+impl QueueServiceApi for QueueService {
+    async fn leave_queue(
+        &self,
+        request: tonic::Request<LeaveQueueRequest>,
+    ) -> Result<tonic::Response<LeaveQueueResponse>, tonic::Status> {
+        let utxo_id = self.authenticate(&request).await?;
+
+        if self.waiter.is_seated(utxo_id).await {
+            log::debug!("utxo {utxo_id} can't leave, it's already in a room");
+            return Err(tonic::Status::failed_precondition(
+                "participant is already in a room",
+            ));
+        }
+
+        let removed = self.waiter.evict(utxo_id).await.map_err(|err| {
+            log::error!("failed to remove utxo {utxo_id} from the queue: {err}");
+            tonic::Status::internal("internal error")
+        })?;
+
+        if !removed {
+            return Err(tonic::Status::not_found("utxo is not queued"));
+        }
+
+        log::debug!("utxo {utxo_id} left the queue");
+
+        Ok(tonic::Response::new(LeaveQueueResponse {}))
+    }
+
+    async fn get_queue_status(
+        &self,
+        request: tonic::Request<GetQueueStatusRequest>,
+    ) -> Result<tonic::Response<GetQueueStatusResponse>, tonic::Status> {
+        let claims = self
+            .tokens_generator
+            .decode_shuffle_token(&request)
+            .map_err(|err| {
+                log::debug!("failed to decode token: {err}");
+                tonic::Status::unauthenticated("invalid token")
+            })?;
+
+        if self.waiter.is_seated(claims.utxo_id).await {
+            return Ok(tonic::Response::new(GetQueueStatusResponse {
+                ready: true,
+                ..Default::default()
+            }));
+        }
+
+        self.waiter.touch(claims.utxo_id).await;
+
+        let Some(status) = self.waiter.queue_status(claims.utxo_id).await else {
+            return Err(tonic::Status::not_found("utxo is not queued"));
+        };
+
+        Ok(tonic::Response::new(GetQueueStatusResponse {
+            ready: false,
+            position: status.position as u64,
+            queue_size: status.size as u64,
+            needed: status.needed as u64,
+            estimated_wait_secs: status
+                .estimated_wait
+                .map(|wait| wait.as_secs())
+                .unwrap_or_default(),
+        }))
+    }
+
+    type WatchRoomReadyStream = ReceiverStream<Result<RoomReadyEvent, tonic::Status>>;
+
+    async fn watch_room_ready(
+        &self,
+        request: tonic::Request<WatchRoomReadyRequest>,
+    ) -> Result<tonic::Response<Self::WatchRoomReadyStream>, tonic::Status> {
+        let claims = self
+            .tokens_generator
+            .decode_shuffle_token(&request)
+            .map_err(|err| {
+                log::debug!("failed to decode token: {err}");
+                tonic::Status::unauthenticated("invalid token")
+            })?;
+
+        if !self.waiter.is_seated(claims.utxo_id).await
+            && self.waiter.queue_status(claims.utxo_id).await.is_none()
+        {
+            return Err(tonic::Status::not_found("utxo is not queued"));
+        }
+
+        // Watch before checking the participant, so the room created in between
+        // is not missed.
+        let ready = self.rooms.watch_ready(claims.utxo_id).await;
+
+        let (event_sender, event_receiver) = channel(1);
+        let service = self.service.clone();
+        let waiter = self.waiter.clone();
+        let tokens_generator = self.tokens_generator.clone();
+
+        tokio::spawn(async move {
+            tokio::pin!(ready);
+
+            // Connected watcher is treated as polling, so its queue entry doesn't expire.
+            let mut renew = tokio::time::interval(waiter.queue_ttl() / 2);
+
+            while service.get_participant(&claims.utxo_id).await.is_none() {
+                tokio::select! {
+                    notified = &mut ready => {
+                        if notified.is_err() {
+                            return; // watcher is dropped without the room
+                        }
+                        break;
+                    }
+                    _ = renew.tick() => {
+                        if !waiter.touch(claims.utxo_id).await
+                            && !waiter.is_seated(claims.utxo_id).await
+                        {
+                            let status = tonic::Status::not_found("utxo is not queued");
+                            let _ = event_sender.send(Err(status)).await;
+                            return;
+                        }
+                    }
+                    _ = event_sender.closed() => return,
+                }
+            }
+
+            let event = tokens_generator
+                .generate_shuffle_token(claims.token, claims.amount, claims.utxo_id)
+                .map(|room_access_token| RoomReadyEvent { room_access_token })
+                .map_err(|err| {
+                    log::error!("failed to generate token: {err}");
+                    tonic::Status::internal("internal error")
+                });
+
+            let _ = event_sender.send(event).await;
+        });
+
+        Ok(tonic::Response::new(ReceiverStream::new(event_receiver)))
+    }
+
+    async fn get_transfer_status(
+        &self,
+        request: tonic::Request<GetTransferStatusRequest>,
+    ) -> Result<tonic::Response<GetTransferStatusResponse>, tonic::Status> {
+        let claims = self
+            .tokens_generator
+            .decode_room_token(&request)
+            .map_err(|err| {
+                log::debug!("failed to decode token: {err}");
+                tonic::Status::unauthenticated("invalid token")
+            })?;
+
+        let transfer = self
+            .rooms
+            .transfer(&claims.room_id)
+            .await
+            .map_err(|err| {
+                log::error!("failed to get transfer of room {}: {err}", claims.room_id);
+                tonic::Status::internal("internal error")
+            })?
+            .ok_or_else(|| tonic::Status::not_found("transfer not found"))?;
+
+        Ok(tonic::Response::new(GetTransferStatusResponse {
+            tx_hash: transfer.tx_hash.as_bytes().to_vec(),
+            status: transfer.status.as_str().to_string(),
+            block_number: transfer.block_number.unwrap_or_default(),
+        }))
+    }
+}
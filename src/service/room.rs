@@ -1,10 +1,9 @@
-use crate::service::auth::TokensGenerator;
-use coin_shuffle_contracts_bindings::utxo::types::Output;
-use coin_shuffle_contracts_bindings::utxo::{self, Contract};
+use crate::chain::{self, PendingTransfer, SimulationError, UtxoContract};
+use crate::metrics;
+use crate::service::{auth::TokensGenerator, transfer::SentTransfer};
+use crate::waiter::{Participant, Waiter};
+use coin_shuffle_contracts_bindings::utxo::types::{Input, Output};
 use coin_shuffle_core::service::types::Room;
-use ethers_middleware::SignerMiddleware;
-use ethers_providers::{Http, Provider};
-use ethers_signers::LocalWallet;
 
 use coin_shuffle_core::service::{types::EncodedOutput, Service};
 use coin_shuffle_protos::v1::{
@@ -12,36 +11,202 @@ use coin_shuffle_protos::v1::{
     TxSigningOutputs,
 };
 use coin_shuffle_protos::v1::{ShuffleError, ShuffleEvent, ShuffleInfo};
-use ethers_core::{abi::ethereum_types::Signature, types::U256};
+use ethers_core::{
+    abi::ethereum_types::Signature,
+    types::{Address, U256},
+};
 use eyre::{Context, Result};
 use rsa::{PublicKeyParts, RsaPublicKey};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::pin::Pin;
 use tokio::{
-    sync::mpsc::{Receiver as StreamReceiver, Sender as StreamSender},
-    time::{interval_at, Duration, Instant, Interval},
+    sync::{
+        mpsc::{Receiver as StreamReceiver, Sender as StreamSender},
+        oneshot,
+    },
+    time::{sleep, Duration, Instant, Sleep},
 };
+use uuid::Uuid;
 
-pub const DEFAULT_ROUND_DEADLINE: Duration = Duration::from_secs(2 * 60);
-
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum RoomEvents {
     ShuffleRound((U256, Vec<EncodedOutput>)),
-    SignedOutput((U256, Signature)),
+    /// Output signature of the participant. Result of the signature verification
+    /// is sent to `reply`, so the participant can retry with a correct one.
+    SignedOutput {
+        utxo_id: U256,
+        signature: Signature,
+        reply: oneshot::Sender<Result<(), tonic::Status>>,
+    },
     AddParticipant {
         utxo_id: U256,
         stream: StreamSender<Result<ShuffleEvent, tonic::Status>>,
         key: RsaPublicKey,
     },
+    /// Request of the current room state.
+    Info(oneshot::Sender<RoomInfo>),
+    /// Close the room by the operator, participants are returned to the queue.
+    Abort {
+        reason: String,
+    },
+}
+
+/// Current state of the room.
+#[derive(Debug, Clone)]
+pub struct RoomInfo {
+    pub id: Uuid,
+    pub token: Address,
+    pub amount: U256,
+    pub phase: RoomPhase,
+    pub participants: Vec<U256>,
+    pub connected: Vec<U256>,
+}
+
+/// Stage of the shuffle the room is currently in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPhase {
+    /// Waiting for participants to connect and send their RSA public keys.
+    Connecting,
+    /// Participants are passing encoded outputs to each other.
+    Shuffling,
+    /// Outputs are distributed, waiting for participants' signatures.
+    Signing,
+}
+
+impl RoomPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomPhase::Connecting => "connecting",
+            RoomPhase::Shuffling => "shuffling",
+            RoomPhase::Signing => "signing",
+        }
+    }
+}
+
+impl fmt::Display for RoomPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomPhase::Connecting => write!(f, "connect"),
+            RoomPhase::Shuffling => write!(f, "shuffle round"),
+            RoomPhase::Signing => write!(f, "signing"),
+        }
+    }
+}
+
+/// Time that participants have to finish each phase of the shuffle.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomDeadlines {
+    /// Time for all participants to connect, counted from the room creation.
+    pub connect: Duration,
+    /// Time for one participant to pass encoded outputs to the next one.
+    pub shuffle_round: Duration,
+    /// Time for all participants to sign the outputs.
+    pub signing: Duration,
+}
+
+impl RoomDeadlines {
+    fn for_phase(&self, phase: RoomPhase) -> Duration {
+        match phase {
+            RoomPhase::Connecting => self.connect,
+            RoomPhase::Shuffling => self.shuffle_round,
+            RoomPhase::Signing => self.signing,
+        }
+    }
+}
+
+/// Report that is sent to every connected participant when the room is closed
+/// because of some of the participants.
+#[derive(Debug, serde::Serialize)]
+pub struct BlameReport {
+    pub room_id: Uuid,
+    pub phase: RoomPhase,
+    pub reason: BlameReason,
+    /// UTXO ids of participants the room is closed because of.
+    pub offenders: Vec<U256>,
+    /// Revert reason of the rejected transfer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlameReason {
+    /// Offenders failed to act before the phase deadline.
+    Deadline,
+    /// Inputs of offenders are spent or removed from the contract.
+    SpentInputs,
+    /// Offenders signed outputs with a key other than the owner of the input.
+    InvalidSignature,
+    /// Contract rejected the transfer for a reason none of the participants
+    /// is found responsible for.
+    RejectedTransfer,
+}
+
+impl BlameReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlameReason::Deadline => "deadline",
+            BlameReason::SpentInputs => "spent_inputs",
+            BlameReason::InvalidSignature => "invalid_signature",
+            BlameReason::RejectedTransfer => "rejected_transfer",
+        }
+    }
+}
+
+/// Error of the transfer submission caused by some of the participants.
+#[derive(thiserror::Error, Debug)]
+#[error("transfer is rejected ({}) because of {offenders:?}", reason.as_str())]
+struct Blamed {
+    reason: BlameReason,
+    offenders: Vec<U256>,
+    details: Option<String>,
+}
+
+/// Returns UTXOs from `utxo_ids` that are spent or don't exist in the contract.
+pub async fn spent_utxos(contract: &UtxoContract, utxo_ids: &[U256]) -> Result<Vec<U256>> {
+    let mut spent = Vec::new();
+
+    for utxo_id in utxo_ids.iter().cloned() {
+        let utxo = contract
+            .get_utxo_by_id(utxo_id)
+            .await
+            .with_context(|| format!("failed to get utxo {utxo_id} from contract"))?;
+
+        let is_spent = match utxo {
+            Some(utxo) => utxo.is_spent,
+            None => true, // removed from the contract
+        };
+
+        if is_spent {
+            spent.push(utxo_id);
+        }
+    }
+
+    Ok(spent)
 }
 
 pub struct RoomConnectionManager {
     room: Room,
-
-    deadline: Interval,
+    phase: RoomPhase,
+    phase_started_at: Instant,
+    /// Index of the participant in `room.participants` whose shuffle round is expected.
+    current_round: usize,
+    /// Outputs of the transfer participants sign, known after the last shuffle round.
+    outputs: Vec<Output>,
+    /// Participants that have already sent their output signature.
+    signed: HashSet<U256>,
+    /// Shuffle transaction, set when it's sent and the room is closed.
+    sent: Option<PendingTransfer>,
+
+    deadlines: RoomDeadlines,
+    deadline: Pin<Box<Sleep>>,
     events: StreamReceiver<RoomEvents>,
     participant_streams: HashMap<U256, StreamSender<Result<ShuffleEvent, tonic::Status>>>,
     service: Service,
-    utxo_contract: utxo::Connector<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    waiter: Waiter,
+    utxo_contract: UtxoContract,
     token_generator: TokensGenerator,
 }
 
@@ -50,43 +215,98 @@ impl RoomConnectionManager {
         events: StreamReceiver<RoomEvents>,
         room: Room,
         service: Service,
+        waiter: Waiter,
         token_generator: TokensGenerator,
-        contract: utxo::Connector<SignerMiddleware<Provider<Http>, LocalWallet>>,
+        contract: UtxoContract,
+        deadlines: RoomDeadlines,
     ) -> Self {
         Self {
             service,
+            waiter,
             events,
             room,
+            phase: RoomPhase::Connecting,
+            phase_started_at: Instant::now(),
+            current_round: 0,
+            outputs: Vec::new(),
+            signed: HashSet::new(),
+            sent: None,
             token_generator,
             utxo_contract: contract,
             participant_streams: HashMap::new(),
-            deadline: interval_at(
-                Instant::now() + DEFAULT_ROUND_DEADLINE,
-                DEFAULT_ROUND_DEADLINE,
-            ),
+            deadlines,
+            deadline: Box::pin(sleep(deadlines.connect)),
         }
     }
 
-    pub fn set_deadline(&mut self, deadline: Interval) -> &mut RoomConnectionManager {
-        self.deadline = deadline;
-        self
+    /// Switch room to the next phase (or next shuffle round) and restart the deadline for it.
+    fn set_phase(&mut self, phase: RoomPhase) {
+        if self.phase != phase {
+            self.observe_phase_duration();
+            self.phase = phase;
+            self.phase_started_at = Instant::now();
+        }
+
+        self.deadline
+            .as_mut()
+            .reset(Instant::now() + self.deadlines.for_phase(phase));
+    }
+
+    fn observe_phase_duration(&self) {
+        metrics::PHASE_DURATION
+            .with_label_values(&[self.phase.as_str()])
+            .observe(self.phase_started_at.elapsed().as_secs_f64());
     }
 
     pub async fn run(&mut self) {
         log::info!("New room is opened: {}", self.room.id);
         loop {
             tokio::select! {
-                _ = self.deadline.tick() => {
-                    // TODO: Add the huilo list returning
-                    log::debug!(target: "room", "room_id={} deadline is over", self.room.id);
+                _ = self.deadline.as_mut() => {
+                    self.observe_phase_duration();
+                    metrics::ROOMS_ABORTED
+                        .with_label_values(&[&format!("{}_deadline", self.phase.as_str())])
+                        .inc();
+
+                    let offenders = self.offenders();
+                    log::info!(
+                        target: "room",
+                        "room_id={} {} deadline is over, stalled participants: {:?}",
+                        self.room.id,
+                        self.phase,
+                        offenders,
+                    );
+
+                    self.blame(BlameReason::Deadline, offenders, None).await;
                     return;
                 }
                 Some(event) = self.events.recv() => {
                     log::debug!(target: "room", "room_id={} new event {:?}", self.room.id, event);
 
-                    match self.handle_event(event.clone()).await {
+                    if let RoomEvents::Abort { reason } = event {
+                        self.observe_phase_duration();
+                        metrics::ROOMS_ABORTED.with_label_values(&["admin"]).inc();
+
+                        self.abort(reason).await;
+                        return;
+                    }
+
+                    match self.handle_event(event).await {
+                        Err(err) if err.downcast_ref::<Blamed>().is_some() => {
+                            let Ok(blamed) = err.downcast::<Blamed>() else {
+                                return
+                            };
+                            log::info!(target: "room", "room_id={} {blamed}", self.room.id);
+                            self.observe_phase_duration();
+                            metrics::ROOMS_ABORTED.with_label_values(&[blamed.reason.as_str()]).inc();
+
+                            self.blame(blamed.reason, blamed.offenders, blamed.details).await;
+                            return
+                        }
                         Err(err) => {
                             log::error!(target: "room", "room_id={} {err:?}", self.room.id);
+                            self.observe_phase_duration();
+                            metrics::ROOMS_ABORTED.with_label_values(&["error"]).inc();
                             for (_, stream) in self.participant_streams.iter_mut() {
                                 let _ = stream.send(Ok(ShuffleEvent {
                                     body: Some(Body::Error(ShuffleError {
@@ -94,7 +314,13 @@ impl RoomConnectionManager {
                                     })),
                                 })).await;
                             }
-                            self.service.clear_room(&self.room.id).await;
+                            self.clear().await;
+                            return
+                        }
+                        Ok(()) if self.sent.is_some() => {
+                            log::info!(target: "room", "room_id={} shuffle is finished", self.room.id);
+                            self.observe_phase_duration();
+                            metrics::ROOMS_FINISHED.inc();
                             return
                         }
                         Ok(()) => {
@@ -118,11 +344,34 @@ impl RoomConnectionManager {
             RoomEvents::ShuffleRound((utxo_id, decoded_outputs)) => {
                 self.event_shuffle_round(utxo_id, decoded_outputs).await?
             }
-            RoomEvents::SignedOutput((utxo_id, signature)) => {
+            RoomEvents::SignedOutput {
+                utxo_id,
+                signature,
+                reply,
+            } => {
+                if let Err(status) = self.verify_output_signature(utxo_id, &signature).await {
+                    log::debug!(
+                        target: "event",
+                        "room_id={} signature of utxo_id={} is rejected: {}",
+                        self.room.id,
+                        utxo_id,
+                        status.message()
+                    );
+                    // participant may have stopped waiting for the answer
+                    let _ = reply.send(Err(status));
+                    return Ok(());
+                }
+
+                let _ = reply.send(Ok(()));
                 self.event_signed_output(utxo_id, signature)
                     .await
                     .context("Failed to handle signed output")?;
             }
+            RoomEvents::Info(reply) => {
+                // requester may have stopped waiting for the answer
+                let _ = reply.send(self.info());
+            }
+            RoomEvents::Abort { .. } => {} // handled in the events loop
         }
 
         Ok(())
@@ -161,6 +410,8 @@ impl RoomConnectionManager {
             .await
             .context("failed to distribute public keys")?;
 
+        self.current_round = 0;
+        self.set_phase(RoomPhase::Shuffling);
         self.send_encoded_outputs(&self.room.participants[0], Vec::new())
             .await?;
 
@@ -175,7 +426,7 @@ impl RoomConnectionManager {
     }
 
     pub async fn event_shuffle_round(
-        &self,
+        &mut self,
         utxo_id: U256,
         decoded_outputs: Vec<EncodedOutput>,
     ) -> Result<()> {
@@ -187,14 +438,20 @@ impl RoomConnectionManager {
             .pass_decoded_outputs(&utxo_id, decoded_outputs.clone())
             .await?
         {
-            Finished(outputs) => self
-                .distribute_outputs(outputs)
-                .await
-                .context("failed to distribute outputs")?,
-            Round(current_round) => self
-                .send_encoded_outputs(&self.room.participants[current_round], decoded_outputs)
-                .await
-                .context("failed to send outputs to the next participant")?,
+            Finished(outputs) => {
+                self.outputs = outputs.clone();
+                self.set_phase(RoomPhase::Signing);
+                self.distribute_outputs(outputs)
+                    .await
+                    .context("failed to distribute outputs")?
+            }
+            Round(current_round) => {
+                self.current_round = current_round;
+                self.set_phase(RoomPhase::Shuffling);
+                self.send_encoded_outputs(&self.room.participants[current_round], decoded_outputs)
+                    .await
+                    .context("failed to send outputs to the next participant")?
+            }
         };
 
         log::info!(target: "event", "shuffle round: utxo_id={} end", utxo_id);
@@ -202,38 +459,272 @@ impl RoomConnectionManager {
         Ok(())
     }
 
-    pub async fn event_signed_output(&self, utxo_id: U256, signature: Signature) -> Result<()> {
+    pub async fn event_signed_output(&mut self, utxo_id: U256, signature: Signature) -> Result<()> {
         log::info!(target: "event", "room_id={} signed output: utxo_id={}", self.room.id, utxo_id);
 
-        let Some((outputs, inputs)) = self.service
+        let signed_outputs = self
+            .service
             .pass_signature(&self.room.id, &utxo_id, signature)
             .await
-            .context("Failed to save output signature")? else {
-                return Ok(()) // That means that still not all participants have signed outputs;
-            };
+            .context("Failed to save output signature")?;
+        self.signed.insert(utxo_id);
 
-        let tx_hash = self
-            .utxo_contract
-            .transfer(inputs, outputs)
-            .await
-            .context("Failed to send transaction")?;
+        let Some((outputs, inputs)) = signed_outputs else {
+            return Ok(()); // That means that still not all participants have signed outputs;
+        };
+
+        self.simulate_transfer(&inputs, &outputs).await?;
+
+        let transfer_started_at = Instant::now();
+        let transfer = self.utxo_contract.send_transfer(&inputs, &outputs).await;
+
+        let result = if transfer.is_ok() {
+            "success"
+        } else {
+            "failure"
+        };
+        metrics::TRANSFER_DURATION.observe(transfer_started_at.elapsed().as_secs_f64());
+        metrics::TRANSFERS.with_label_values(&[result]).inc();
+
+        let transfer = transfer.context("Failed to send transaction")?;
+        let tx_hash = transfer.tx_hash();
+
+        log::info!(target: "room", "room_id={} transaction {tx_hash:?} is sent", self.room.id);
 
         for (_, stream) in self.participant_streams.iter() {
-            stream
+            // transaction is sent anyway, participant that has disconnected can
+            // look it up later
+            let _ = stream
                 .send(Ok(ShuffleEvent {
                     body: Some(Body::ShuffleTxHash(ShuffleTxHash {
                         tx_hash: tx_hash.as_bytes().to_vec(),
                     })),
                 }))
-                .await
-                .context("failed to send tx_hash to participant")?;
+                .await;
         }
 
+        // Participants stay seated until the transaction is final, as their inputs
+        // are not spent on chain before it's mined and could join other queues.
         self.service.clear_room(&self.room.id).await;
+        self.sent = Some(transfer);
 
         Ok(())
     }
 
+    /// Checks that the output signature is made by the owner of the participant's
+    /// input for the outputs of this room, so a wrong signature is rejected before
+    /// it fails the transfer.
+    async fn verify_output_signature(
+        &self,
+        utxo_id: U256,
+        signature: &Signature,
+    ) -> Result<(), tonic::Status> {
+        if self.phase != RoomPhase::Signing {
+            return Err(tonic::Status::failed_precondition(
+                "outputs are not distributed yet",
+            ));
+        }
+
+        if self.signed.contains(&utxo_id) {
+            return Err(tonic::Status::already_exists(
+                "output signature is already accepted",
+            ));
+        }
+
+        let utxo = self
+            .utxo_contract
+            .get_utxo_by_id(utxo_id)
+            .await
+            .map_err(|err| {
+                log::error!(
+                    target: "room",
+                    "room_id={} failed to get utxo {utxo_id} from contract: {err}",
+                    self.room.id,
+                );
+                tonic::Status::internal("internal error")
+            })?;
+
+        let owner = match utxo {
+            Some(utxo) if !utxo.is_spent => utxo.owner,
+            _ => return Err(tonic::Status::failed_precondition("utxo is already spent")),
+        };
+
+        match chain::recover_input_signer(utxo_id, &self.outputs, signature.as_bytes()) {
+            Ok(signer) if signer == owner => Ok(()),
+            Ok(signer) => Err(tonic::Status::invalid_argument(format!(
+                "outputs are signed by {signer:?}, not by the utxo owner"
+            ))),
+            Err(err) => Err(tonic::Status::invalid_argument(format!(
+                "invalid signature: {err}"
+            ))),
+        }
+    }
+
+    /// Dry runs the transfer before it is sent. If the contract rejects it, finds
+    /// participants whose inputs are spent or signatures are invalid and returns
+    /// [`Blamed`] error with them.
+    async fn simulate_transfer(&self, inputs: &[Input], outputs: &[Output]) -> Result<()> {
+        let reason = match self.utxo_contract.simulate_transfer(inputs, outputs).await {
+            Ok(()) => return Ok(()),
+            Err(SimulationError::Reverted(reason)) => reason,
+            Err(err) => return Err(err.into()),
+        };
+
+        log::info!(target: "room", "room_id={} transfer simulation is reverted: {reason}", self.room.id);
+
+        let mut spent = Vec::new();
+        let mut invalid = Vec::new();
+
+        for input in inputs {
+            let utxo = self
+                .utxo_contract
+                .get_utxo_by_id(input.id)
+                .await
+                .with_context(|| format!("failed to get utxo {} from contract", input.id))?;
+
+            let owner = match utxo {
+                Some(utxo) if !utxo.is_spent => utxo.owner,
+                _ => {
+                    spent.push(input.id);
+                    continue;
+                }
+            };
+
+            match chain::recover_input_signer(input.id, outputs, &input.signature) {
+                Ok(signer) if signer == owner => {}
+                _ => invalid.push(input.id),
+            }
+        }
+
+        let (reason, offenders, details) = if !spent.is_empty() {
+            (BlameReason::SpentInputs, spent, None)
+        } else if !invalid.is_empty() {
+            (BlameReason::InvalidSignature, invalid, None)
+        } else {
+            (BlameReason::RejectedTransfer, Vec::new(), Some(reason))
+        };
+
+        Err(Blamed {
+            reason,
+            offenders,
+            details,
+        }
+        .into())
+    }
+
+    /// Shuffle transaction of the finished room with streams of its participants,
+    /// `None` if the room is closed without the transaction.
+    pub fn into_sent_transfer(self) -> Option<SentTransfer> {
+        Some(SentTransfer {
+            room_id: self.room.id,
+            participants: self.room.participants,
+            transfer: self.sent?,
+            streams: self.participant_streams.into_values().collect(),
+        })
+    }
+
+    /// Participants that haven't done their part of the current phase in time.
+    fn offenders(&self) -> Vec<U256> {
+        match self.phase {
+            RoomPhase::Connecting => self
+                .room
+                .participants
+                .iter()
+                .filter(|utxo_id| !self.participant_streams.contains_key(utxo_id))
+                .cloned()
+                .collect(),
+            RoomPhase::Shuffling => vec![self.room.participants[self.current_round]],
+            RoomPhase::Signing => self
+                .room
+                .participants
+                .iter()
+                .filter(|utxo_id| !self.signed.contains(utxo_id))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Send blame report to connected participants, close the room and return
+    /// honest participants to the queue, so they don't need to join again.
+    async fn blame(&mut self, reason: BlameReason, offenders: Vec<U256>, details: Option<String>) {
+        let report = BlameReport {
+            room_id: self.room.id,
+            phase: self.phase,
+            reason,
+            offenders,
+            details,
+        };
+
+        let error = match serde_json::to_string(&report) {
+            Ok(error) => error,
+            Err(err) => {
+                log::error!(target: "room", "room_id={} failed to encode blame report: {err}", self.room.id);
+                format!("{report:?}")
+            }
+        };
+
+        self.close(error, &report.offenders).await;
+    }
+
+    /// Close the room on the operator's request.
+    async fn abort(&mut self, reason: String) {
+        log::info!(target: "room", "room_id={} aborted: {reason}", self.room.id);
+
+        self.close(format!("room is aborted by operator: {reason}"), &[])
+            .await;
+    }
+
+    /// Send error to connected participants, clear the room and return participants,
+    /// except offenders, to the queue.
+    async fn close(&mut self, error: String, offenders: &[U256]) {
+        for (_, stream) in self.participant_streams.iter() {
+            let _ = stream
+                .send(Ok(ShuffleEvent {
+                    body: Some(Body::Error(ShuffleError {
+                        error: error.clone(),
+                    })),
+                }))
+                .await;
+        }
+
+        for participant in self.clear().await {
+            if offenders.contains(&participant.utxo_id) {
+                continue;
+            }
+
+            if let Err(err) = self
+                .waiter
+                .add_participant(self.room.token, self.room.amount, participant)
+                .await
+            {
+                log::error!(
+                    target: "room",
+                    "room_id={} failed to return utxo_id={} to the queue: {err}",
+                    self.room.id,
+                    participant.utxo_id,
+                );
+            }
+        }
+    }
+
+    /// Remove the room from the service and release its participants in the waiter.
+    /// Returns released participants.
+    async fn clear(&self) -> Vec<Participant> {
+        self.service.clear_room(&self.room.id).await;
+        self.waiter.release(&self.room.participants).await
+    }
+
+    fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.room.id,
+            token: self.room.token,
+            amount: self.room.amount,
+            phase: self.phase,
+            participants: self.room.participants.clone(),
+            connected: self.participant_streams.keys().cloned().collect(),
+        }
+    }
+
     ///! Send event with RSA public keys that are required to decode outputs
     ///! to each participant.
     pub async fn distribute_public_keys(
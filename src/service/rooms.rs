@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use coin_shuffle_core::service::{types::Room, Service};
+use ethers_core::{types::U256, utils::hex};
+use eyre::Context;
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender as StreamSender},
+    oneshot, Mutex,
+};
+use uuid::Uuid;
+
+use crate::{
+    chain::{PendingTransfer, UtxoContract},
+    metrics,
+    storage::{QueueEntry, Storage, StoredRoom, TransferRecord},
+    waiter::{FilledQueue, Participant, Waiter},
+};
+
+use super::{
+    auth::TokensGenerator,
+    room::{spent_utxos, RoomConnectionManager, RoomDeadlines, RoomEvents, RoomInfo},
+    transfer::{self, SentTransfer},
+};
+
+/// Time to wait for the room to report its state.
+const ROOM_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Registry of opened rooms. Every room created in [`Service`] gets its own
+/// [`RoomConnectionManager`] that is running until the room is closed.
+#[derive(Clone)]
+pub struct Rooms {
+    service: Service,
+    waiter: Waiter,
+    tokens_generator: TokensGenerator,
+    utxo_contract: UtxoContract,
+    deadlines: RoomDeadlines,
+    storage: Arc<dyn Storage>,
+
+    rooms: Arc<Mutex<HashMap<Uuid, StreamSender<RoomEvents>>>>,
+    /// Rooms that were interrupted by the service restart, with the reason
+    /// participants are told about.
+    aborted: Arc<Mutex<HashMap<Uuid, AbortedRoom>>>,
+    /// Participants waiting to be notified when their room is created.
+    ready_watchers: Arc<Mutex<HashMap<U256, Vec<oneshot::Sender<()>>>>>,
+}
+
+struct AbortedRoom {
+    reason: String,
+    participants: Vec<U256>,
+}
+
+impl Rooms {
+    pub fn new(
+        service: Service,
+        waiter: Waiter,
+        tokens_generator: TokensGenerator,
+        utxo_contract: UtxoContract,
+        deadlines: RoomDeadlines,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
+        Self {
+            service,
+            waiter,
+            tokens_generator,
+            utxo_contract,
+            deadlines,
+            storage,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            aborted: Arc::new(Mutex::new(HashMap::new())),
+            ready_watchers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Aborts rooms that were in progress before the service restart and returns
+    /// their participants to the queue, as the shuffle state can't be restored.
+    /// Rooms that have sent the shuffle transaction are left to [`Self::resume_transfers`].
+    ///
+    /// Participants are returned to the queues in the storage only, so it should
+    /// be called before the queues are restored from it.
+    pub async fn abort_interrupted(&self) -> eyre::Result<()> {
+        for room in self.storage.rooms().await? {
+            if self.storage.transfer(&room.id).await?.is_some() {
+                continue;
+            }
+
+            let reason = format!(
+                "room {} was aborted: service restarted, participant is returned to the queue",
+                room.id
+            );
+            log::info!("{reason}");
+
+            let joined_at = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            let entries = room
+                .participants
+                .iter()
+                .map(|participant| QueueEntry {
+                    token: room.token,
+                    amount: room.amount,
+                    utxo_id: participant.utxo_id,
+                    owner: participant.owner,
+                    joined_at,
+                })
+                .collect();
+
+            self.storage.requeue_room(&room.id, entries).await?;
+            metrics::ROOMS_ABORTED.with_label_values(&["restart"]).inc();
+
+            self.aborted.lock().await.insert(
+                room.id,
+                AbortedRoom {
+                    reason,
+                    participants: room.participants.iter().map(|p| p.utxo_id).collect(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Resumes tracking of the shuffle transactions that were not final before
+    /// the service restart. Participants are not connected anymore, so the outcome
+    /// is only recorded.
+    pub async fn resume_transfers(&self) -> eyre::Result<()> {
+        let transfers = self
+            .storage
+            .pending_transfers()
+            .await
+            .context("failed to get pending transfers")?;
+
+        let mut rooms: HashMap<Uuid, StoredRoom> = self
+            .storage
+            .rooms()
+            .await
+            .context("failed to get rooms")?
+            .into_iter()
+            .map(|room| (room.id, room))
+            .collect();
+
+        for transfer in transfers {
+            log::info!(
+                "resume tracking of transaction {:?} of room {}",
+                transfer.tx_hash,
+                transfer.room_id
+            );
+
+            let participants = rooms
+                .remove(&transfer.room_id)
+                .map(|room| room.participants)
+                .unwrap_or_default();
+            self.waiter.seat(&participants).await;
+
+            tokio::spawn(finish_transfer(
+                self.waiter.clone(),
+                self.utxo_contract.clone(),
+                self.storage.clone(),
+                SentTransfer {
+                    room_id: transfer.room_id,
+                    participants: participants.iter().map(|p| p.utxo_id).collect(),
+                    transfer: PendingTransfer::resumed(transfer.tx_hash),
+                    streams: Vec::new(),
+                },
+            ));
+        }
+
+        // Rooms with the final transfer are left if the service has stopped
+        // before they were removed.
+        for room_id in rooms.keys() {
+            self.storage.remove_room(room_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Shuffle transaction of the room with its latest known state.
+    pub async fn transfer(&self, room_id: &Uuid) -> eyre::Result<Option<TransferRecord>> {
+        self.storage.transfer(room_id).await
+    }
+
+    /// Reason why the room was aborted, if it was.
+    pub async fn aborted_reason(&self, room_id: &Uuid) -> Option<String> {
+        self.aborted
+            .lock()
+            .await
+            .get(room_id)
+            .map(|room| room.reason.clone())
+    }
+
+    /// Reason why the room of the participant was aborted, if it was.
+    pub async fn participant_aborted_reason(&self, utxo_id: &U256) -> Option<String> {
+        self.aborted
+            .lock()
+            .await
+            .values()
+            .find(|room| room.participants.contains(utxo_id))
+            .map(|room| room.reason.clone())
+    }
+
+    /// Creates and opens a room for every queue filled in [`Waiter`].
+    pub async fn listen(self, mut filled_queues: Receiver<FilledQueue>) {
+        while let Some(filled) = filled_queues.recv().await {
+            let Some(filled) = self.drop_spent(filled).await else {
+                continue;
+            };
+
+            // Lock rooms before the room creation, so participants that see the room
+            // in the service always find its connection manager.
+            let mut rooms = self.rooms.lock().await;
+
+            let room = self
+                .service
+                .create_room(
+                    filled.token,
+                    filled.amount,
+                    filled.participants.iter().map(|p| p.utxo_id).collect(),
+                )
+                .await;
+
+            log::debug!("room created: {room:?}");
+            log::info!(
+                "room {} participants are ordered with seed {}",
+                room.id,
+                hex::encode(filled.seed),
+            );
+
+            if let Err(err) = self
+                .storage
+                .insert_room(StoredRoom {
+                    id: room.id,
+                    token: room.token,
+                    amount: room.amount,
+                    participants: filled.participants,
+                    seed: filled.seed,
+                })
+                .await
+            {
+                log::error!("failed to save room {}: {err}", room.id);
+
+                // Room goes on without being saved, but its participants must not
+                // be restored to the queues while they are in it.
+                if let Err(err) = self.storage.remove_participants(&room.participants).await {
+                    log::error!("failed to remove participants of room {}: {err}", room.id);
+                }
+            }
+
+            let participants = room.participants.clone();
+            rooms.insert(room.id, self.open(room));
+            drop(rooms);
+
+            self.notify_ready(&participants).await;
+        }
+    }
+
+    /// Checks that inputs of the filled queue are still unspent. If some of them
+    /// are spent, the rest of participants are returned to the queue, so the
+    /// room is formed again without spent inputs.
+    async fn drop_spent(&self, filled: FilledQueue) -> Option<FilledQueue> {
+        let utxo_ids: Vec<U256> = filled.participants.iter().map(|p| p.utxo_id).collect();
+
+        let spent = match spent_utxos(&self.utxo_contract, &utxo_ids).await {
+            Ok(spent) => spent,
+            Err(err) => {
+                // Inputs are checked again before the transfer.
+                log::error!("failed to check inputs of the filled queue: {err}");
+                return Some(filled);
+            }
+        };
+
+        if spent.is_empty() {
+            return Some(filled);
+        }
+
+        log::info!(
+            "spent inputs are dropped from the queue ({:?}, {}): {spent:?}",
+            filled.token,
+            filled.amount,
+        );
+
+        if let Err(err) = self.storage.remove_participants(&spent).await {
+            log::error!("failed to remove spent inputs from storage: {err}");
+        }
+
+        let returned: Vec<Participant> = self
+            .waiter
+            .release(&utxo_ids)
+            .await
+            .into_iter()
+            .filter(|participant| !spent.contains(&participant.utxo_id))
+            .collect();
+        let waiter = self.waiter.clone();
+
+        // Participants are returned in background, as the filled queue is sent
+        // back to this listener.
+        tokio::spawn(async move {
+            if let Err(err) = waiter
+                .requeue(filled.token, filled.amount, &returned, &filled.joined_at)
+                .await
+            {
+                log::error!("failed to return participants {returned:?} to the queue: {err}");
+            }
+        });
+
+        None
+    }
+
+    /// Returns receiver that is notified when the room of the participant is created.
+    pub async fn watch_ready(&self, utxo_id: U256) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let mut watchers = self.ready_watchers.lock().await;
+
+        // Forget watchers that are not waiting anymore, e.g. participants that
+        // left the queue or disconnected.
+        watchers.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+
+        watchers.entry(utxo_id).or_default().push(sender);
+
+        receiver
+    }
+
+    async fn notify_ready(&self, participants: &[U256]) {
+        let mut watchers = self.ready_watchers.lock().await;
+
+        for utxo_id in participants {
+            for sender in watchers.remove(utxo_id).unwrap_or_default() {
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    /// Starts connection manager of the room, so the deadline of the connect
+    /// phase starts from the room creation. Returns sender of the room events.
+    fn open(&self, room: Room) -> StreamSender<RoomEvents> {
+        let room_id = room.id;
+        let (internal_events_sender, internal_events_receiver) = channel(10);
+
+        let mut manager = RoomConnectionManager::new(
+            internal_events_receiver,
+            room,
+            self.service.clone(),
+            self.waiter.clone(),
+            self.tokens_generator.clone(),
+            self.utxo_contract.clone(),
+            self.deadlines,
+        );
+
+        let rooms = self.rooms.clone();
+        let waiter = self.waiter.clone();
+        let storage = self.storage.clone();
+        let utxo_contract = self.utxo_contract.clone();
+        metrics::ACTIVE_ROOMS.inc();
+        tokio::spawn(async move {
+            manager.run().await;
+            rooms.lock().await.remove(&room_id);
+            metrics::ACTIVE_ROOMS.dec();
+
+            match manager.into_sent_transfer() {
+                Some(sent) => finish_transfer(waiter, utxo_contract, storage, sent).await,
+                None => remove_room(&storage, &room_id).await,
+            }
+        });
+
+        internal_events_sender
+    }
+
+    /// Returns number of opened rooms.
+    pub async fn count(&self) -> usize {
+        self.rooms.lock().await.len()
+    }
+
+    pub async fn get(&self, room_id: &Uuid) -> Option<StreamSender<RoomEvents>> {
+        self.rooms.lock().await.get(room_id).cloned()
+    }
+
+    /// Returns state of all opened rooms. Rooms that are busy and don't answer
+    /// in time are skipped.
+    pub async fn list(&self) -> Vec<RoomInfo> {
+        let rooms: Vec<_> = self
+            .rooms
+            .lock()
+            .await
+            .iter()
+            .map(|(room_id, stream)| (*room_id, stream.clone()))
+            .collect();
+
+        let mut infos = Vec::with_capacity(rooms.len());
+
+        for (room_id, stream) in rooms {
+            let (reply, info) = oneshot::channel();
+
+            if stream.send(RoomEvents::Info(reply)).await.is_err() {
+                continue; // room is closed
+            }
+
+            match tokio::time::timeout(ROOM_INFO_TIMEOUT, info).await {
+                Ok(Ok(info)) => infos.push(info),
+                Ok(Err(_)) => {} // room is closed
+                Err(_) => log::warn!("room {room_id} didn't report its state in time"),
+            }
+        }
+
+        infos
+    }
+
+    /// Sends abort event to the room. Returns `false` if there is no such room.
+    pub async fn abort(&self, room_id: &Uuid, reason: String) -> bool {
+        let Some(stream) = self.get(room_id).await else {
+            return false;
+        };
+
+        stream.send(RoomEvents::Abort { reason }).await.is_ok()
+    }
+}
+
+/// Tracks the shuffle transaction of the room until it's final, then releases
+/// its participants and removes the room from the storage. Until then the room
+/// is kept, so the transaction is resumed after restart instead of the room
+/// being aborted.
+async fn finish_transfer(
+    waiter: Waiter,
+    contract: UtxoContract,
+    storage: Arc<dyn Storage>,
+    sent: SentTransfer,
+) {
+    let room_id = sent.room_id;
+    let participants = sent.participants.clone();
+
+    transfer::track(contract, storage.clone(), sent).await;
+
+    waiter.release(&participants).await;
+    remove_room(&storage, &room_id).await;
+}
+
+async fn remove_room(storage: &Arc<dyn Storage>, room_id: &Uuid) {
+    if let Err(err) = storage.remove_room(room_id).await {
+        log::error!("failed to remove room {room_id} from storage: {err}");
+    }
+}
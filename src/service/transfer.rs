@@ -0,0 +1,133 @@
+use std::{sync::Arc, time::Duration};
+
+use coin_shuffle_protos::v1::{shuffle_event::Body, ShuffleError, ShuffleEvent, ShuffleTxHash};
+use ethers_core::types::{H256, U256};
+use tokio::{sync::mpsc::Sender as StreamSender, time::sleep};
+use uuid::Uuid;
+
+use crate::{
+    chain::{PendingTransfer, TransferOutcome, TransferPoll, TransferStatus, UtxoContract},
+    metrics,
+    storage::{Storage, TransferRecord},
+};
+
+/// How often the node is asked about the sent transaction.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shuffle transaction sent by the room, with streams of the participants
+/// that are told about its outcome.
+pub struct SentTransfer {
+    pub room_id: Uuid,
+    /// Inputs of the transaction, that are kept seated until it's final.
+    pub participants: Vec<U256>,
+    pub transfer: PendingTransfer,
+    pub streams: Vec<StreamSender<Result<ShuffleEvent, tonic::Status>>>,
+}
+
+/// Watches the shuffle transaction until it's final, records its state and
+/// sends it to the participants: hash of every replacement of the stuck
+/// transaction, then the outcome, after which the streams are closed.
+///
+/// Confirmed transaction is reported with the hash of the mined one, while only
+/// reverted and dropped ones are reported as errors. The recorded outcome can be
+/// looked up by participants later with the room access token.
+pub(super) async fn track(contract: UtxoContract, storage: Arc<dyn Storage>, sent: SentTransfer) {
+    let SentTransfer {
+        room_id,
+        mut transfer,
+        streams,
+        ..
+    } = sent;
+
+    save(
+        &storage,
+        room_id,
+        transfer.tx_hash(),
+        None,
+        TransferStatus::Pending,
+    )
+    .await;
+
+    let outcome = loop {
+        sleep(POLL_INTERVAL).await;
+
+        match contract.poll_transfer(&mut transfer).await {
+            Ok(TransferPoll::Pending) => {}
+            Ok(TransferPoll::Replaced(tx_hash)) => {
+                log::info!(
+                    "room_id={room_id} transaction is resubmitted with bumped fees: {tx_hash:?}"
+                );
+                metrics::TRANSFER_REPLACEMENTS.inc();
+
+                save(&storage, room_id, tx_hash, None, TransferStatus::Pending).await;
+                send(&streams, tx_hash_event(tx_hash)).await;
+            }
+            Ok(TransferPoll::Final(outcome)) => break outcome,
+            Err(err) => log::warn!(
+                "room_id={room_id} failed to get state of transaction {:?}: {err}",
+                transfer.tx_hash()
+            ),
+        }
+    };
+
+    let TransferOutcome {
+        status,
+        tx_hash,
+        block_number,
+    } = outcome;
+
+    log::info!(
+        "room_id={room_id} transaction {tx_hash:?} is {} in block {block_number:?}",
+        status.as_str(),
+    );
+    metrics::TRANSFER_OUTCOMES
+        .with_label_values(&[status.as_str()])
+        .inc();
+
+    save(&storage, room_id, tx_hash, block_number, status).await;
+
+    let event = match status {
+        TransferStatus::Confirmed => tx_hash_event(tx_hash),
+        _ => Body::Error(ShuffleError {
+            error: format!("shuffle transaction {tx_hash:?} is {}", status.as_str()),
+        }),
+    };
+
+    send(&streams, event).await;
+}
+
+fn tx_hash_event(tx_hash: H256) -> Body {
+    Body::ShuffleTxHash(ShuffleTxHash {
+        tx_hash: tx_hash.as_bytes().to_vec(),
+    })
+}
+
+async fn send(streams: &[StreamSender<Result<ShuffleEvent, tonic::Status>>], body: Body) {
+    for stream in streams {
+        // participant may have disconnected after the transaction was sent
+        let _ = stream
+            .send(Ok(ShuffleEvent {
+                body: Some(body.clone()),
+            }))
+            .await;
+    }
+}
+
+async fn save(
+    storage: &Arc<dyn Storage>,
+    room_id: Uuid,
+    tx_hash: H256,
+    block_number: Option<u64>,
+    status: TransferStatus,
+) {
+    let transfer = TransferRecord {
+        room_id,
+        tx_hash,
+        status,
+        block_number,
+    };
+
+    if let Err(err) = storage.save_transfer(transfer).await {
+        log::error!("failed to save transfer of room {room_id}: {err}");
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers_core::types::U256;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::chain::TransferStatus;
+
+use super::{QueueEntry, Storage, StoredRoom, TransferRecord};
+
+/// Storage that keeps state only while the process is running.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    entries: Arc<Mutex<Vec<QueueEntry>>>,
+    rooms: Arc<Mutex<HashMap<Uuid, StoredRoom>>>,
+    transfers: Arc<Mutex<HashMap<Uuid, TransferRecord>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn push_participant(&self, entry: QueueEntry) -> eyre::Result<()> {
+        self.entries.lock().await.push(entry);
+        Ok(())
+    }
+
+    async fn remove_participants(&self, utxo_ids: &[U256]) -> eyre::Result<()> {
+        self.entries
+            .lock()
+            .await
+            .retain(|entry| !utxo_ids.contains(&entry.utxo_id));
+        Ok(())
+    }
+
+    async fn queue_entries(&self) -> eyre::Result<Vec<QueueEntry>> {
+        Ok(self.entries.lock().await.clone())
+    }
+
+    async fn insert_room(&self, room: StoredRoom) -> eyre::Result<()> {
+        let mut entries = self.entries.lock().await;
+
+        entries.retain(|entry| {
+            !room
+                .participants
+                .iter()
+                .any(|participant| participant.utxo_id == entry.utxo_id)
+        });
+        self.rooms.lock().await.insert(room.id, room);
+
+        Ok(())
+    }
+
+    async fn remove_room(&self, room_id: &Uuid) -> eyre::Result<()> {
+        self.rooms.lock().await.remove(room_id);
+        Ok(())
+    }
+
+    async fn requeue_room(&self, room_id: &Uuid, entries: Vec<QueueEntry>) -> eyre::Result<()> {
+        let mut queued = self.entries.lock().await;
+
+        queued.retain(|entry| {
+            !entries
+                .iter()
+                .any(|requeued| requeued.utxo_id == entry.utxo_id)
+        });
+        queued.extend(entries);
+        self.rooms.lock().await.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn rooms(&self) -> eyre::Result<Vec<StoredRoom>> {
+        Ok(self.rooms.lock().await.values().cloned().collect())
+    }
+
+    async fn save_transfer(&self, transfer: TransferRecord) -> eyre::Result<()> {
+        self.transfers
+            .lock()
+            .await
+            .insert(transfer.room_id, transfer);
+        Ok(())
+    }
+
+    async fn transfer(&self, room_id: &Uuid) -> eyre::Result<Option<TransferRecord>> {
+        Ok(self.transfers.lock().await.get(room_id).cloned())
+    }
+
+    async fn pending_transfers(&self) -> eyre::Result<Vec<TransferRecord>> {
+        Ok(self
+            .transfers
+            .lock()
+            .await
+            .values()
+            .filter(|transfer| transfer.status == TransferStatus::Pending)
+            .cloned()
+            .collect())
+    }
+}
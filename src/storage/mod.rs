@@ -0,0 +1,88 @@
+//! Persistent state of the service: queues of waiting participants, rooms
+//! that are in progress and shuffle transactions sent by the rooms.
+//!
+//! [`Storage`] is used as a write-through backend: working state is kept in
+//! memory by [`crate::waiter::Waiter`] and [`crate::service::Protocol`], and
+//! every change of it is mirrored to the storage, so it can be restored after
+//! restart.
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+use ethers_core::types::{Address, H256, U256};
+use uuid::Uuid;
+
+use crate::{chain::TransferStatus, waiter::Participant};
+
+/// Participant waiting in the (token, amount) queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueEntry {
+    pub token: Address,
+    pub amount: U256,
+    pub utxo_id: U256,
+    pub owner: Address,
+    /// Unix timestamp of the arrival to the queue.
+    pub joined_at: u64,
+}
+
+/// Room that was created, but not finished yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredRoom {
+    pub id: Uuid,
+    pub token: Address,
+    pub amount: U256,
+    pub participants: Vec<Participant>,
+    /// Seed the participants were picked from the queue and ordered with.
+    pub seed: [u8; 32],
+}
+
+/// Shuffle transaction of the room and its latest known state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferRecord {
+    pub room_id: Uuid,
+    pub tx_hash: H256,
+    pub status: TransferStatus,
+    pub block_number: Option<u64>,
+}
+
+/// Big-endian bytes of the value, the way UTXO ids and amounts are stored and
+/// sent to clients.
+pub fn u256_to_bytes(value: &U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    bytes
+}
+
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Appends participant to the end of its queue.
+    async fn push_participant(&self, entry: QueueEntry) -> eyre::Result<()>;
+
+    /// Removes participants from their queues.
+    async fn remove_participants(&self, utxo_ids: &[U256]) -> eyre::Result<()>;
+
+    /// Returns all queued participants in order of arrival.
+    async fn queue_entries(&self) -> eyre::Result<Vec<QueueEntry>>;
+
+    /// Inserts the room and removes its participants from their queues at once,
+    /// so they are either queued or in the room whenever the service stops.
+    async fn insert_room(&self, room: StoredRoom) -> eyre::Result<()>;
+
+    async fn remove_room(&self, room_id: &Uuid) -> eyre::Result<()>;
+
+    /// Removes the room and queues `entries` of its participants at once, so
+    /// they are either in the room or queued whenever the service stops.
+    async fn requeue_room(&self, room_id: &Uuid, entries: Vec<QueueEntry>) -> eyre::Result<()>;
+
+    async fn rooms(&self) -> eyre::Result<Vec<StoredRoom>>;
+
+    /// Inserts the transfer of the room or replaces the existing one.
+    async fn save_transfer(&self, transfer: TransferRecord) -> eyre::Result<()>;
+
+    async fn transfer(&self, room_id: &Uuid) -> eyre::Result<Option<TransferRecord>>;
+
+    /// Returns transfers that are not final yet.
+    async fn pending_transfers(&self) -> eyre::Result<Vec<TransferRecord>>;
+}
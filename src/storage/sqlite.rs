@@ -0,0 +1,508 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ethers_core::types::{Address, H256, U256};
+use eyre::{eyre, Context};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::{chain::TransferStatus, waiter::Participant};
+
+use super::{u256_to_bytes, QueueEntry, Storage, StoredRoom, TransferRecord};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queue_entries (
+        seq       INTEGER PRIMARY KEY AUTOINCREMENT,
+        token     BLOB NOT NULL,
+        amount    BLOB NOT NULL,
+        utxo_id   BLOB NOT NULL,
+        owner     BLOB NOT NULL,
+        joined_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS rooms (
+        id     TEXT PRIMARY KEY,
+        token  BLOB NOT NULL,
+        amount BLOB NOT NULL,
+        seed   BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS room_participants (
+        room_id  TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        utxo_id  BLOB NOT NULL,
+        owner    BLOB NOT NULL,
+        PRIMARY KEY (room_id, position)
+    );
+
+    CREATE TABLE IF NOT EXISTS transfers (
+        room_id      TEXT PRIMARY KEY,
+        tx_hash      BLOB NOT NULL,
+        status       TEXT NOT NULL,
+        block_number INTEGER
+    );
+";
+
+/// Embedded storage in the SQLite database file.
+///
+/// Queries are blocking, so they are run on the blocking thread pool, as some
+/// of them are made while the queues are locked.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("failed to open database: {}", path.display()))?;
+
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .context("failed to enable foreign keys")?;
+        conn.execute_batch(SCHEMA)
+            .context("failed to apply database schema")?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> eyre::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> eyre::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| eyre!("database connection is poisoned"))?;
+
+            f(&mut conn)
+        })
+        .await
+        .context("database task failed")?
+    }
+}
+
+fn delete_queue_entries(conn: &Connection, utxo_ids: &[U256]) -> eyre::Result<()> {
+    for utxo_id in utxo_ids {
+        conn.execute(
+            "DELETE FROM queue_entries WHERE utxo_id = ?1",
+            params![u256_to_bytes(utxo_id)],
+        )
+        .context("failed to delete queue entry")?;
+    }
+
+    Ok(())
+}
+
+fn insert_queue_entry(conn: &Connection, entry: &QueueEntry) -> eyre::Result<()> {
+    conn.execute(
+        "INSERT INTO queue_entries (token, amount, utxo_id, owner, joined_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            entry.token.as_bytes(),
+            u256_to_bytes(&entry.amount),
+            u256_to_bytes(&entry.utxo_id),
+            entry.owner.as_bytes(),
+            entry.joined_at,
+        ],
+    )
+    .context("failed to insert queue entry")?;
+
+    Ok(())
+}
+
+fn transfer_from_row(row: &Row) -> eyre::Result<TransferRecord> {
+    let room_id: String = row.get(0)?;
+    let status: String = row.get(2)?;
+
+    Ok(TransferRecord {
+        room_id: Uuid::parse_str(&room_id)
+            .wrap_err_with(|| format!("invalid room id: {room_id}"))?,
+        tx_hash: H256::from_slice(&row.get::<_, Vec<u8>>(1)?),
+        status: status.parse()?,
+        block_number: row.get(3)?,
+    })
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn push_participant(&self, entry: QueueEntry) -> eyre::Result<()> {
+        self.with_conn(move |conn| insert_queue_entry(conn, &entry))
+            .await
+    }
+
+    async fn remove_participants(&self, utxo_ids: &[U256]) -> eyre::Result<()> {
+        let utxo_ids = utxo_ids.to_vec();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            delete_queue_entries(&tx, &utxo_ids)?;
+
+            tx.commit()
+                .context("failed to commit queue entries removal")
+        })
+        .await
+    }
+
+    async fn queue_entries(&self) -> eyre::Result<Vec<QueueEntry>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT token, amount, utxo_id, owner, joined_at FROM queue_entries ORDER BY seq",
+            )?;
+
+            let entries = stmt
+                .query_map([], |row| {
+                    Ok(QueueEntry {
+                        token: Address::from_slice(&row.get::<_, Vec<u8>>(0)?),
+                        amount: U256::from_big_endian(&row.get::<_, Vec<u8>>(1)?),
+                        utxo_id: U256::from_big_endian(&row.get::<_, Vec<u8>>(2)?),
+                        owner: Address::from_slice(&row.get::<_, Vec<u8>>(3)?),
+                        joined_at: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()
+                .context("failed to read queue entries")?;
+
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn insert_room(&self, room: StoredRoom) -> eyre::Result<()> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO rooms (id, token, amount, seed) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    room.id.to_string(),
+                    room.token.as_bytes(),
+                    u256_to_bytes(&room.amount),
+                    room.seed,
+                ],
+            )
+            .context("failed to insert room")?;
+
+            for (position, participant) in room.participants.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO room_participants (room_id, position, utxo_id, owner) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        room.id.to_string(),
+                        position,
+                        u256_to_bytes(&participant.utxo_id),
+                        participant.owner.as_bytes(),
+                    ],
+                )
+                .context("failed to insert room participant")?;
+            }
+
+            let utxo_ids: Vec<U256> = room.participants.iter().map(|p| p.utxo_id).collect();
+            delete_queue_entries(&tx, &utxo_ids)?;
+
+            tx.commit().context("failed to commit room")
+        })
+        .await
+    }
+
+    async fn remove_room(&self, room_id: &Uuid) -> eyre::Result<()> {
+        let room_id = room_id.to_string();
+
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM rooms WHERE id = ?1", params![room_id])
+                .context("failed to delete room")?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn requeue_room(&self, room_id: &Uuid, entries: Vec<QueueEntry>) -> eyre::Result<()> {
+        let room_id = room_id.to_string();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            let utxo_ids: Vec<U256> = entries.iter().map(|entry| entry.utxo_id).collect();
+            delete_queue_entries(&tx, &utxo_ids)?;
+
+            for entry in entries {
+                insert_queue_entry(&tx, &entry)?;
+            }
+
+            tx.execute("DELETE FROM rooms WHERE id = ?1", params![room_id])
+                .context("failed to delete room")?;
+
+            tx.commit().context("failed to commit room requeue")
+        })
+        .await
+    }
+
+    async fn rooms(&self) -> eyre::Result<Vec<StoredRoom>> {
+        self.with_conn(|conn| {
+            let mut rooms_stmt = conn.prepare("SELECT id, token, amount, seed FROM rooms")?;
+            let mut participants_stmt = conn.prepare(
+                "SELECT utxo_id, owner FROM room_participants WHERE room_id = ?1 ORDER BY position",
+            )?;
+
+            let rows = rooms_stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        Address::from_slice(&row.get::<_, Vec<u8>>(1)?),
+                        U256::from_big_endian(&row.get::<_, Vec<u8>>(2)?),
+                        row.get::<_, [u8; 32]>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+                .context("failed to read rooms")?;
+
+            let mut rooms = Vec::with_capacity(rows.len());
+
+            for (id, token, amount, seed) in rows {
+                let participants = participants_stmt
+                    .query_map(params![id], |row| {
+                        Ok(Participant {
+                            utxo_id: U256::from_big_endian(&row.get::<_, Vec<u8>>(0)?),
+                            owner: Address::from_slice(&row.get::<_, Vec<u8>>(1)?),
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("failed to read room participants")?;
+
+                rooms.push(StoredRoom {
+                    id: Uuid::parse_str(&id).wrap_err_with(|| format!("invalid room id: {id}"))?,
+                    token,
+                    amount,
+                    participants,
+                    seed,
+                });
+            }
+
+            Ok(rooms)
+        })
+        .await
+    }
+
+    async fn save_transfer(&self, transfer: TransferRecord) -> eyre::Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO transfers (room_id, tx_hash, status, block_number) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    transfer.room_id.to_string(),
+                    transfer.tx_hash.as_bytes(),
+                    transfer.status.as_str(),
+                    transfer.block_number,
+                ],
+            )
+            .context("failed to save transfer")?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn transfer(&self, room_id: &Uuid) -> eyre::Result<Option<TransferRecord>> {
+        let room_id = room_id.to_string();
+
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT room_id, tx_hash, status, block_number FROM transfers WHERE room_id = ?1",
+                params![room_id],
+                |row| Ok(transfer_from_row(row)),
+            )
+            .optional()
+            .context("failed to read transfer")?
+            .transpose()
+        })
+        .await
+    }
+
+    async fn pending_transfers(&self) -> eyre::Result<Vec<TransferRecord>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT room_id, tx_hash, status, block_number FROM transfers WHERE status = ?1",
+            )?;
+
+            let rows = stmt
+                .query_map(params![TransferStatus::Pending.as_str()], |row| {
+                    Ok(transfer_from_row(row))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+                .context("failed to read transfers")?;
+
+            rows.into_iter().collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> SqliteStorage {
+        SqliteStorage::open(":memory:").unwrap()
+    }
+
+    fn entry(utxo_id: u64, joined_at: u64) -> QueueEntry {
+        QueueEntry {
+            token: Address::repeat_byte(0x11),
+            amount: U256::exp10(18),
+            utxo_id: U256::MAX - utxo_id,
+            owner: Address::from_low_u64_be(utxo_id),
+            joined_at,
+        }
+    }
+
+    fn room(entries: &[QueueEntry]) -> StoredRoom {
+        StoredRoom {
+            id: Uuid::new_v4(),
+            token: entries[0].token,
+            amount: entries[0].amount,
+            participants: entries
+                .iter()
+                .map(|entry| Participant {
+                    utxo_id: entry.utxo_id,
+                    owner: entry.owner,
+                })
+                .collect(),
+            seed: [7; 32],
+        }
+    }
+
+    fn transfer(room_id: Uuid, status: TransferStatus) -> TransferRecord {
+        TransferRecord {
+            room_id,
+            tx_hash: H256::random(),
+            status,
+            block_number: None,
+        }
+    }
+
+    async fn room_participants_count(storage: &SqliteStorage) -> i64 {
+        storage
+            .with_conn(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM room_participants", [], |row| {
+                    row.get(0)
+                })
+                .context("failed to count room participants")
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_queue_entries_in_order_of_insertion() {
+        let storage = storage();
+        let entries = vec![entry(3, 10), entry(1, 20), entry(2, 5)];
+
+        for entry in entries.iter() {
+            storage.push_participant(entry.clone()).await.unwrap();
+        }
+
+        assert_eq!(storage.queue_entries().await.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn removes_queue_entries_of_participants() {
+        let storage = storage();
+
+        for utxo_id in 1..=3 {
+            storage.push_participant(entry(utxo_id, 0)).await.unwrap();
+        }
+
+        storage
+            .remove_participants(&[entry(2, 0).utxo_id])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.queue_entries().await.unwrap(),
+            vec![entry(1, 0), entry(3, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_participants_from_queue_to_inserted_room() {
+        let storage = storage();
+        let entries: Vec<QueueEntry> = (1..=3).map(|utxo_id| entry(utxo_id, 0)).collect();
+
+        for entry in entries.iter() {
+            storage.push_participant(entry.clone()).await.unwrap();
+        }
+
+        let room = room(&entries[..2]);
+        storage.insert_room(room.clone()).await.unwrap();
+
+        assert_eq!(storage.rooms().await.unwrap(), vec![room]);
+        assert_eq!(
+            storage.queue_entries().await.unwrap(),
+            vec![entries[2].clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn removes_room_with_its_participants() {
+        let storage = storage();
+        let room = room(&[entry(1, 0), entry(2, 0)]);
+
+        storage.insert_room(room.clone()).await.unwrap();
+        assert_eq!(room_participants_count(&storage).await, 2);
+
+        storage.remove_room(&room.id).await.unwrap();
+
+        assert!(storage.rooms().await.unwrap().is_empty());
+        assert_eq!(room_participants_count(&storage).await, 0);
+    }
+
+    #[tokio::test]
+    async fn requeues_room_participants() {
+        let storage = storage();
+        let entries = vec![entry(1, 10), entry(2, 20)];
+        let room = room(&entries);
+
+        storage.insert_room(room.clone()).await.unwrap();
+        storage
+            .requeue_room(&room.id, entries.clone())
+            .await
+            .unwrap();
+
+        assert!(storage.rooms().await.unwrap().is_empty());
+        assert_eq!(room_participants_count(&storage).await, 0);
+        assert_eq!(storage.queue_entries().await.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn replaces_transfer_and_returns_only_pending_ones() {
+        let storage = storage();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let pending = transfer(first, TransferStatus::Pending);
+        storage.save_transfer(pending.clone()).await.unwrap();
+        storage
+            .save_transfer(transfer(second, TransferStatus::Pending))
+            .await
+            .unwrap();
+
+        let confirmed = TransferRecord {
+            block_number: Some(42),
+            ..transfer(second, TransferStatus::Confirmed)
+        };
+        storage.save_transfer(confirmed.clone()).await.unwrap();
+
+        assert_eq!(storage.transfer(&second).await.unwrap(), Some(confirmed));
+        assert_eq!(storage.transfer(&Uuid::new_v4()).await.unwrap(), None);
+        assert_eq!(storage.pending_transfers().await.unwrap(), vec![pending]);
+    }
+}
@@ -7,9 +7,80 @@
 ///! The queue is represented by uniqe keys of the room: ERC20 token address and
 ///! amount that will be shuffled. Participant in that room represented by his UTXO
 ///! identifier.
+mod policy;
 mod queue;
 
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
 use ethers_core::types::{Address, U256};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+};
+
+use crate::{metrics, storage::Storage};
+
+pub use self::policy::{CompositionPolicy, OwnerLimit};
+use self::queue::Formation;
+
+/// How often queues are checked for expired participants.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/// How often queues are checked for participants waiting past the room formation timeout.
+const FORMATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// UTXO waiting for a room with its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Participant {
+    pub utxo_id: U256,
+    pub owner: Address,
+}
+
+/// Participants of a filled queue that are ready to be placed in a room.
+#[derive(Debug)]
+pub struct FilledQueue {
+    pub token: Address,
+    pub amount: U256,
+    /// Participants in the shuffle order.
+    pub participants: Vec<Participant>,
+    /// Seed the participants are selected and ordered with.
+    pub seed: [u8; 32],
+    /// Unix timestamps of the participants' arrival to the queue, so they keep
+    /// their place if the room is not formed.
+    pub joined_at: HashMap<U256, u64>,
+}
+
+/// State of a participant waiting in a queue.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStatus {
+    /// Position in order of arrival, starting from 1. It's not the order the
+    /// participants are taken to the room in, which is random.
+    pub position: usize,
+    /// Number of participants in the queue.
+    pub size: usize,
+    /// Number of participants still needed to form the next room.
+    pub needed: usize,
+    /// Estimated time to the next room formation, if join rate of the queue is known.
+    pub estimated_wait: Option<Duration>,
+}
+
+/// Rules of the room formation.
+pub struct WaiterOptions {
+    /// Number of participants that should be in a room to start shuffle.
+    pub min_participants: usize,
+    /// Room is formed right away when that many participants are queued.
+    pub max_participants: usize,
+    /// Room with less than max participants is formed when the oldest
+    /// participant has waited that long.
+    pub formation_timeout: Duration,
+    /// Rules participants of the same room should satisfy.
+    pub policies: Vec<Box<dyn CompositionPolicy>>,
+    /// Participants that haven't polled the service for longer are dropped from queues.
+    pub queue_ttl: Duration,
+}
 
 #[derive(Clone)]
 pub struct Waiter {
@@ -17,33 +88,271 @@ pub struct Waiter {
     queue: queue::QueuesStorage,
     ///! Number of participants that should be in a room to start shuffle.
     min_participants: usize,
+    ///! Room is formed right away when that many participants are queued.
+    max_participants: usize,
+    ///! Room with less than max participants is formed when the oldest
+    ///! participant has waited that long.
+    formation_timeout: Duration,
+    ///! Rules participants of the same room should satisfy.
+    policies: Arc<Vec<Box<dyn CompositionPolicy>>>,
+    ///! Participants that haven't polled the service for longer are dropped from queues.
+    queue_ttl: Duration,
+    ///! Channel where filled queues are sent to be turned into rooms.
+    filled: Sender<FilledQueue>,
+    ///! Tokens for which new participants are not accepted.
+    paused: Arc<Mutex<HashSet<Address>>>,
+    ///! Participants taken from the queues that are in rooms now.
+    seated: Arc<Mutex<HashMap<U256, Address>>>,
 }
 
 impl Waiter {
-    pub fn new(min_participants: usize) -> Self {
-        Self {
-            queue: queue::QueuesStorage::new(),
-            min_participants,
+    /// Creates waiter and the receiving side of filled queues.
+    pub fn new(options: WaiterOptions, storage: Arc<dyn Storage>) -> (Self, Receiver<FilledQueue>) {
+        let (filled, filled_receiver) = channel(10);
+
+        let waiter = Self {
+            queue: queue::QueuesStorage::new(storage),
+            min_participants: options.min_participants,
+            max_participants: options.max_participants,
+            formation_timeout: options.formation_timeout,
+            policies: Arc::new(options.policies),
+            queue_ttl: options.queue_ttl,
+            filled,
+            paused: Arc::new(Mutex::new(HashSet::new())),
+            seated: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        (waiter, filled_receiver)
+    }
+
+    /// Restores queues saved in the persistent storage.
+    pub async fn restore(&self) -> eyre::Result<()> {
+        for (token, amount) in self.queue.restore().await? {
+            self.send_if_filled(token, amount).await?;
         }
+
+        Ok(())
     }
 
-    /// Adds a participant to the queue. Returns participants if the queue is filled.
+    /// Adds a participant to the queue. Sends participants to the filled queues
+    /// channel if the queue is filled.
+    ///
+    /// Returns `false` if the participant is already waiting in any queue or is
+    /// in a room, so the same UTXO can't take several places.
     pub async fn add_participant(
         &self,
         token: Address,
         amount: U256,
-        participant: U256,
-    ) -> Option<Vec<U256>> {
-        self.queue.push(token, amount, participant).await;
-
-        if self.is_filled(token, amount).await {
-            Some(self.queue.pop(token, amount).await)
-        } else {
-            None
+        participant: Participant,
+    ) -> eyre::Result<bool> {
+        {
+            let seated = self.seated.lock().await;
+
+            if seated.contains_key(&participant.utxo_id)
+                || !self.queue.push(token, amount, participant).await?
+            {
+                return Ok(false);
+            }
+        }
+
+        self.send_if_filled(token, amount).await?;
+
+        Ok(true)
+    }
+
+    /// Forgets participants of a closed room, so they can join queues again.
+    /// Returns released participants with their owners.
+    pub async fn release(&self, utxo_ids: &[U256]) -> Vec<Participant> {
+        let mut seated = self.seated.lock().await;
+
+        utxo_ids
+            .iter()
+            .filter_map(|utxo_id| {
+                seated.remove(utxo_id).map(|owner| Participant {
+                    utxo_id: *utxo_id,
+                    owner,
+                })
+            })
+            .collect()
+    }
+
+    /// Takes participants admitted by the composition policies out of the queue
+    /// and sends them to the filled queues channel if the queue is full or its
+    /// oldest participant has waited past the formation timeout.
+    async fn send_if_filled(&self, token: Address, amount: U256) -> eyre::Result<()> {
+        let taken = {
+            // Participants are taken and seated at once, so they are never seen
+            // as absent by concurrent joins.
+            let mut seated = self.seated.lock().await;
+
+            let Some(taken) = self.queue.take(token, amount, &self.formation()).await? else {
+                return Ok(());
+            };
+
+            seated.extend(taken.participants.iter().map(|p| (p.utxo_id, p.owner)));
+
+            taken
+        };
+
+        if let Err(err) = self
+            .filled
+            .send(FilledQueue {
+                token,
+                amount,
+                participants: taken.participants,
+                seed: taken.seed,
+                joined_at: taken.joined_at,
+            })
+            .await
+        {
+            log::error!("failed to send filled queue: {err}");
+        }
+
+        Ok(())
+    }
+
+    /// Returns released participants of a room that wasn't formed to the queue,
+    /// keeping their arrival time, so they don't lose their place and wait
+    /// toward the formation timeout.
+    pub async fn requeue(
+        &self,
+        token: Address,
+        amount: U256,
+        participants: &[Participant],
+        joined_at: &HashMap<U256, u64>,
+    ) -> eyre::Result<()> {
+        {
+            let seated = self.seated.lock().await;
+
+            let participants: Vec<Participant> = participants
+                .iter()
+                .filter(|participant| !seated.contains_key(&participant.utxo_id))
+                .cloned()
+                .collect();
+
+            self.queue
+                .reinsert(token, amount, &participants, joined_at)
+                .await;
         }
+
+        self.send_if_filled(token, amount).await
+    }
+
+    /// Removes participant from the queue. Returns `false` if participant is not queued.
+    pub async fn evict(&self, utxo_id: U256) -> eyre::Result<bool> {
+        self.queue.remove(utxo_id).await
+    }
+
+    /// Renews the queue entry of the participant, so it doesn't expire. Returns
+    /// `false` if participant is not queued.
+    pub async fn touch(&self, utxo_id: U256) -> bool {
+        self.queue.touch(utxo_id).await
+    }
+
+    pub fn queue_ttl(&self) -> Duration {
+        self.queue_ttl
+    }
+
+    /// Drops participants that haven't polled the service for longer than the
+    /// queue TTL, so rooms are not formed with wallets that went offline.
+    pub async fn sweep_expired(self) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            match self.queue.remove_expired(self.queue_ttl).await {
+                Ok(0) => {}
+                Ok(expired) => {
+                    log::info!("{expired} participants are dropped from queues as expired");
+                    metrics::QUEUE_EXPIRED.inc_by(expired as u64);
+                }
+                Err(err) => log::error!("failed to remove expired participants: {err}"),
+            }
+        }
+    }
+
+    /// Marks participants as taken to a room without taking them from a queue,
+    /// for rooms restored after restart.
+    pub async fn seat(&self, participants: &[Participant]) {
+        self.seated
+            .lock()
+            .await
+            .extend(participants.iter().map(|p| (p.utxo_id, p.owner)));
+    }
+
+    /// Returns `true` if participant is taken from the queue to a room.
+    pub async fn is_seated(&self, utxo_id: U256) -> bool {
+        self.seated.lock().await.contains_key(&utxo_id)
+    }
+
+    /// Returns state of the participant in its queue, `None` if participant is
+    /// not queued.
+    pub async fn queue_status(&self, utxo_id: U256) -> Option<QueueStatus> {
+        let position = self.queue.position(utxo_id, &self.formation()).await?;
+        let needed = self.min_participants.saturating_sub(position.admitted);
+        let timeout_left = self.formation_timeout.saturating_sub(position.oldest_wait);
+
+        // Room is formed when the queue is full or, with enough participants, on timeout.
+        let estimated_wait = match position.arrival_interval {
+            Some(interval) => {
+                let to_max = self.max_participants.saturating_sub(position.admitted);
+                let to_max = Duration::from_secs_f64(interval * to_max as f64);
+                let to_min = Duration::from_secs_f64(interval * needed as f64);
+
+                Some(to_max.min(to_min.max(timeout_left)))
+            }
+            None if needed == 0 => Some(timeout_left),
+            None => None,
+        };
+
+        Some(QueueStatus {
+            position: position.position,
+            size: position.size,
+            needed,
+            estimated_wait,
+        })
+    }
+
+    /// Forms rooms from queues where the oldest participant has waited past the
+    /// formation timeout, as such queues may not get new participants soon.
+    pub async fn form_timed_out(self) {
+        let mut interval = tokio::time::interval(FORMATION_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            for (token, amount) in self.queue.keys().await {
+                if let Err(err) = self.send_if_filled(token, amount).await {
+                    log::error!("failed to form room for queue ({token:?}, {amount}): {err}");
+                }
+            }
+        }
+    }
+
+    fn formation(&self) -> Formation<'_> {
+        Formation {
+            min_participants: self.min_participants,
+            max_participants: self.max_participants,
+            timeout: self.formation_timeout,
+            policies: &self.policies,
+        }
+    }
+
+    /// Returns (token, amount) queues with number of participants in them.
+    pub async fn queues(&self) -> Vec<((Address, U256), usize)> {
+        self.queue.lengths().await
+    }
+
+    pub async fn pause(&self, token: Address) {
+        self.paused.lock().await.insert(token);
+    }
+
+    pub async fn resume(&self, token: Address) {
+        self.paused.lock().await.remove(&token);
     }
 
-    async fn is_filled(&self, token: Address, amount: U256) -> bool {
-        self.queue.len(token, amount).await >= self.min_participants
+    pub async fn is_paused(&self, token: Address) -> bool {
+        self.paused.lock().await.contains(&token)
     }
 }
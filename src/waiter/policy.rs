@@ -0,0 +1,103 @@
+use super::Participant;
+
+/// Rule that decides which queued participants can be placed in the same room.
+///
+/// Participants that are not admitted stay in the queue and wait for a later room.
+pub trait CompositionPolicy: Send + Sync {
+    /// Returns `true` if `candidate` can be placed in the room together with
+    /// already `selected` participants.
+    fn admits(&self, selected: &[Participant], candidate: &Participant) -> bool;
+}
+
+/// Limits the number of UTXOs of the same owner in a room, as several of them
+/// in one room reduce the anonymity of other participants.
+pub struct OwnerLimit {
+    max_per_owner: usize,
+}
+
+impl OwnerLimit {
+    pub fn new(max_per_owner: usize) -> Self {
+        Self { max_per_owner }
+    }
+}
+
+impl CompositionPolicy for OwnerLimit {
+    fn admits(&self, selected: &[Participant], candidate: &Participant) -> bool {
+        let same_owner = selected
+            .iter()
+            .filter(|participant| participant.owner == candidate.owner)
+            .count();
+
+        same_owner < self.max_per_owner
+    }
+}
+
+/// Selects participants for a room in the order of `candidates`, skipping those
+/// that are not admitted by all of the `policies`. Candidates are shuffled
+/// beforehand, so the earlier arrivals are not preferred.
+pub(super) fn select(
+    candidates: &[Participant],
+    policies: &[Box<dyn CompositionPolicy>],
+) -> Vec<Participant> {
+    let mut selected = Vec::new();
+
+    for candidate in candidates {
+        if policies
+            .iter()
+            .all(|policy| policy.admits(&selected, candidate))
+        {
+            selected.push(*candidate);
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers_core::types::{Address, U256};
+
+    use super::*;
+
+    fn participant(utxo_id: u64, owner: u64) -> Participant {
+        Participant {
+            utxo_id: U256::from(utxo_id),
+            owner: Address::from_low_u64_be(owner),
+        }
+    }
+
+    fn owner_limit(max_per_owner: usize) -> Vec<Box<dyn CompositionPolicy>> {
+        vec![Box::new(OwnerLimit::new(max_per_owner))]
+    }
+
+    #[test]
+    fn skips_utxos_over_owner_limit() {
+        let queue = [
+            participant(1, 1),
+            participant(2, 1),
+            participant(3, 2),
+            participant(4, 1),
+        ];
+
+        let selected = select(&queue, &owner_limit(1));
+
+        assert_eq!(selected, [participant(1, 1), participant(3, 2)]);
+    }
+
+    #[test]
+    fn admits_utxos_of_one_owner_up_to_limit() {
+        let queue = [
+            participant(1, 1),
+            participant(2, 1),
+            participant(3, 1),
+            participant(4, 2),
+        ];
+
+        let selected = select(&queue, &owner_limit(2));
+
+        assert_eq!(
+            selected,
+            [participant(1, 1), participant(2, 1), participant(4, 2)]
+        );
+    }
+}
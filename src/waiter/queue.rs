@@ -1,44 +1,646 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use ethers_core::types::{Address, U256};
+use ethers_core::{
+    types::{Address, U256},
+    utils::hex,
+};
+use rand::{rngs::OsRng, seq::SliceRandom, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use tokio::sync::Mutex;
 
+use crate::{
+    metrics,
+    storage::{QueueEntry, Storage},
+};
+
+use super::{
+    policy::{self, CompositionPolicy},
+    Participant,
+};
+
+/// Number of the latest arrivals to the queue the join rate is estimated by.
+const RECENT_ARRIVALS: usize = 20;
+
 /// Storage of vectors of participants, where participants is represented by his UTXO id
-/// and key of the queue is a pair of (token address, amount).
+/// with its owner and key of the queue is a pair of (token address, amount).
+///
+/// Every change is mirrored to the persistent [`Storage`].
 #[derive(Clone)]
 pub struct QueuesStorage {
-    queues: Arc<Mutex<HashMap<(Address, U256), Vec<U256>>>>,
+    queues: Arc<Mutex<HashMap<(Address, U256), Queue>>>,
+    storage: Arc<dyn Storage>,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Participants in order of arrival.
+    entries: Vec<Participant>,
+    /// Unix timestamps of the participants' arrival.
+    joined_at: HashMap<U256, u64>,
+    /// Last time participants have shown they are still waiting.
+    last_seen: HashMap<U256, Instant>,
+    /// Unix timestamps of the latest arrivals, including participants that
+    /// have already left the queue.
+    arrivals: VecDeque<u64>,
+}
+
+impl Queue {
+    fn push(&mut self, participant: Participant, joined_at: u64) {
+        self.entries.push(participant);
+        self.joined_at.insert(participant.utxo_id, joined_at);
+        self.last_seen.insert(participant.utxo_id, Instant::now());
+
+        self.arrivals.push_back(joined_at);
+        while self.arrivals.len() > RECENT_ARRIVALS {
+            self.arrivals.pop_front();
+        }
+    }
+
+    /// Puts participant back to its place in order of arrival, without counting
+    /// it as a new arrival.
+    fn reinsert(&mut self, participant: Participant, joined_at: u64) {
+        let arrivals = &self.joined_at;
+        let index = self.entries.partition_point(|entry| {
+            arrivals.get(&entry.utxo_id).copied().unwrap_or_default() <= joined_at
+        });
+
+        self.entries.insert(index, participant);
+        self.joined_at.insert(participant.utxo_id, joined_at);
+        self.last_seen.insert(participant.utxo_id, Instant::now());
+    }
+
+    fn remove(&mut self, utxo_ids: &[U256]) {
+        self.entries
+            .retain(|participant| !utxo_ids.contains(&participant.utxo_id));
+
+        for utxo_id in utxo_ids {
+            self.joined_at.remove(utxo_id);
+            self.last_seen.remove(utxo_id);
+        }
+    }
+
+    /// Time the longest waiting participant is in the queue.
+    fn oldest_wait(&self) -> Duration {
+        let oldest = self
+            .entries
+            .first()
+            .and_then(|participant| self.joined_at.get(&participant.utxo_id));
+
+        match oldest {
+            Some(joined_at) => Duration::from_secs(now().saturating_sub(*joined_at)),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn position(&self, utxo_id: U256) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|participant| participant.utxo_id == utxo_id)
+    }
+
+    /// Queue is empty and has had no arrivals for `ttl`, so it can be forgotten
+    /// without losing the join rate of an active queue.
+    fn is_stale(&self, ttl: Duration) -> bool {
+        let last_arrival = self.arrivals.back().copied().unwrap_or_default();
+
+        self.entries.is_empty() && now().saturating_sub(last_arrival) > ttl.as_secs()
+    }
+
+    /// Average number of seconds between the recent arrivals.
+    fn arrival_interval(&self) -> Option<f64> {
+        let (first, last) = (self.arrivals.front()?, self.arrivals.back()?);
+        let span = last.saturating_sub(*first);
+
+        if self.arrivals.len() < 2 || span == 0 {
+            return None;
+        }
+
+        Some(span as f64 / (self.arrivals.len() - 1) as f64)
+    }
+}
+
+/// Place of a participant in its queue.
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    /// Position in order of arrival, starting from 1.
+    pub position: usize,
+    /// Number of participants in the queue.
+    pub size: usize,
+    /// Number of participants the next room would take from the queue now.
+    pub admitted: usize,
+    /// Average number of seconds between the recent arrivals to the queue.
+    pub arrival_interval: Option<f64>,
+    /// Time the longest waiting participant is in the queue.
+    pub oldest_wait: Duration,
+}
+
+/// Participants taken from the queue for a room.
+pub struct Taken {
+    /// Participants in random order, which is the shuffle order of the room.
+    pub participants: Vec<Participant>,
+    /// Seed of [`ChaCha20Rng`] the participants are selected and ordered with.
+    pub seed: [u8; 32],
+    /// Unix timestamps of the participants' arrival to the queue.
+    pub joined_at: HashMap<U256, u64>,
+}
+
+/// Conditions of taking participants from the queue to a room.
+pub struct Formation<'a> {
+    pub min_participants: usize,
+    /// Room is formed right away when that many participants are admitted.
+    pub max_participants: usize,
+    /// Room with less than max participants is formed only when the oldest
+    /// participant in the queue has waited that long.
+    pub timeout: Duration,
+    /// Rules participants of the same room should satisfy.
+    pub policies: &'a [Box<dyn CompositionPolicy>],
+}
+
+/// Picks participants for a room from the queue in order of arrival, in random
+/// order given by the `seed`, so the room can be reproduced for an audit.
+fn pick(entries: &[Participant], seed: [u8; 32], formation: &Formation) -> Vec<Participant> {
+    let mut candidates = entries.to_vec();
+    candidates.shuffle(&mut ChaCha20Rng::from_seed(seed));
+
+    let mut selected = policy::select(&candidates, formation.policies);
+    selected.truncate(formation.max_participants);
+    selected
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 impl QueuesStorage {
-    pub fn new() -> Self {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
             queues: Arc::new(Mutex::new(HashMap::new())),
+            storage,
         }
     }
 
-    pub async fn push(&self, token: Address, amount: U256, utxo_id: U256) {
+    /// Load queues saved in the persistent storage. Returns keys of the restored queues.
+    pub async fn restore(&self) -> eyre::Result<Vec<(Address, U256)>> {
+        let mut entries = self.storage.queue_entries().await?;
         let mut queues = self.queues.lock().await;
-        let queue = queues.entry((token, amount)).or_insert_with(Vec::new);
-        queue.push(utxo_id);
+
+        // Participants returned to the queue are stored after later arrivals, but
+        // take their place by the arrival time.
+        entries.sort_by_key(|entry| entry.joined_at);
+
+        for entry in entries {
+            queues.entry((entry.token, entry.amount)).or_default().push(
+                Participant {
+                    utxo_id: entry.utxo_id,
+                    owner: entry.owner,
+                },
+                entry.joined_at,
+            );
+        }
+
+        for ((token, amount), queue) in queues.iter() {
+            metrics::set_queue_length(*token, *amount, queue.entries.len());
+        }
+
+        Ok(queues.keys().cloned().collect())
+    }
+
+    /// Pushes participant to the queue. Returns `false` if participant is already
+    /// in this or any other queue.
+    pub async fn push(
+        &self,
+        token: Address,
+        amount: U256,
+        participant: Participant,
+    ) -> eyre::Result<bool> {
+        let mut queues = self.queues.lock().await;
+
+        if queues
+            .values()
+            .any(|queue| queue.position(participant.utxo_id).is_some())
+        {
+            return Ok(false);
+        }
+
+        let joined_at = now();
+
+        self.storage
+            .push_participant(QueueEntry {
+                token,
+                amount,
+                utxo_id: participant.utxo_id,
+                owner: participant.owner,
+                joined_at,
+            })
+            .await?;
+
+        let queue = queues.entry((token, amount)).or_default();
+        queue.push(participant, joined_at);
+        metrics::set_queue_length(token, amount, queue.entries.len());
+
+        Ok(true)
+    }
+
+    /// Takes participants for a room admitted by the composition policies out of
+    /// the queue, if the `formation` conditions are met. Otherwise returns `None`
+    /// and leaves the queue untouched.
+    ///
+    /// Participants are picked from the queue in random order, so neither room
+    /// membership nor the shuffle order depend on the time of arrival. The order
+    /// can be reproduced from the queue in arrival order and the returned seed.
+    pub async fn take(
+        &self,
+        token: Address,
+        amount: U256,
+        formation: &Formation<'_>,
+    ) -> eyre::Result<Option<Taken>> {
+        let mut queues = self.queues.lock().await;
+
+        let Some(queue) = queues.get_mut(&(token, amount)) else {
+            return Ok(None);
+        };
+
+        // Most checks find the queue neither full nor timed out, so they are done
+        // before the selection that needs randomness and the copy of the queue.
+        let size = queue.entries.len();
+        if size < formation.max_participants
+            && (size < formation.min_participants || queue.oldest_wait() < formation.timeout)
+        {
+            return Ok(None);
+        }
+
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+
+        let selected = pick(&queue.entries, seed, formation);
+
+        let is_full = selected.len() == formation.max_participants;
+        let is_timed_out = selected.len() >= formation.min_participants
+            && queue.oldest_wait() >= formation.timeout;
+
+        if !is_full && !is_timed_out {
+            return Ok(None);
+        }
+
+        // Participants stay in the storage until their room is saved, so they are
+        // not lost if the service stops before that.
+        let utxo_ids: Vec<U256> = selected.iter().map(|p| p.utxo_id).collect();
+
+        log::info!(
+            "participants {:?} are taken from queue ({token:?}, {amount}) of {:?} with seed {}",
+            utxo_ids,
+            queue.entries.iter().map(|p| p.utxo_id).collect::<Vec<_>>(),
+            hex::encode(seed),
+        );
+
+        let joined_at = utxo_ids
+            .iter()
+            .filter_map(|utxo_id| Some((*utxo_id, *queue.joined_at.get(utxo_id)?)))
+            .collect();
+
+        queue.remove(&utxo_ids);
+        metrics::set_queue_length(token, amount, queue.entries.len());
+
+        Ok(Some(Taken {
+            participants: selected,
+            seed,
+            joined_at,
+        }))
     }
 
-    /// Clean up the queue and return the list of participants.
-    pub async fn pop(&self, token: Address, amount: U256) -> Vec<U256> {
+    /// Returns participants taken for a room that wasn't formed to the queue at
+    /// their place in order of arrival. Participants that are already queued
+    /// again are skipped.
+    ///
+    /// Participants are still in the storage, as the room is not saved.
+    pub async fn reinsert(
+        &self,
+        token: Address,
+        amount: U256,
+        participants: &[Participant],
+        joined_at: &HashMap<U256, u64>,
+    ) {
         let mut queues = self.queues.lock().await;
-        let queue = queues.entry((token, amount)).or_insert_with(Vec::new);
-        let participants = queue.clone();
-        queue.clear();
-        participants
+
+        for participant in participants {
+            if queues
+                .values()
+                .any(|queue| queue.position(participant.utxo_id).is_some())
+            {
+                continue;
+            }
+
+            let joined_at = joined_at
+                .get(&participant.utxo_id)
+                .copied()
+                .unwrap_or_else(now);
+
+            queues
+                .entry((token, amount))
+                .or_default()
+                .reinsert(*participant, joined_at);
+        }
+
+        if let Some(queue) = queues.get(&(token, amount)) {
+            metrics::set_queue_length(token, amount, queue.entries.len());
+        }
     }
 
-    pub async fn len(&self, token: Address, amount: U256) -> usize {
+    /// Removes participant from the queue it's waiting in. Returns `false` if
+    /// participant is not in any queue.
+    pub async fn remove(&self, utxo_id: U256) -> eyre::Result<bool> {
+        let mut queues = self.queues.lock().await;
+
+        let Some(((token, amount), queue)) = queues
+            .iter_mut()
+            .find(|(_, queue)| queue.position(utxo_id).is_some())
+        else {
+            return Ok(false);
+        };
+
+        self.storage.remove_participants(&[utxo_id]).await?;
+
+        queue.remove(&[utxo_id]);
+        metrics::set_queue_length(*token, *amount, queue.entries.len());
+
+        Ok(true)
+    }
+
+    /// Renews the time participant was last seen. Returns `false` if participant
+    /// is not in any queue.
+    pub async fn touch(&self, utxo_id: U256) -> bool {
+        let mut queues = self.queues.lock().await;
+
+        queues.values_mut().any(|queue| {
+            let Some(last_seen) = queue.last_seen.get_mut(&utxo_id) else {
+                return false;
+            };
+
+            *last_seen = Instant::now();
+            true
+        })
+    }
+
+    /// Removes participants that were not seen for longer than `ttl` from all
+    /// queues, and queues that stay empty for that long. Returns number of
+    /// removed participants.
+    pub async fn remove_expired(&self, ttl: Duration) -> eyre::Result<usize> {
+        let mut queues = self.queues.lock().await;
+        let mut removed = 0;
+
+        for ((token, amount), queue) in queues.iter_mut() {
+            let expired: Vec<U256> = queue
+                .last_seen
+                .iter()
+                .filter(|(_, last_seen)| last_seen.elapsed() > ttl)
+                .map(|(utxo_id, _)| *utxo_id)
+                .collect();
+
+            if expired.is_empty() {
+                continue;
+            }
+
+            self.storage.remove_participants(&expired).await?;
+
+            queue.remove(&expired);
+            metrics::set_queue_length(*token, *amount, queue.entries.len());
+            removed += expired.len();
+        }
+
+        queues.retain(|_, queue| !queue.is_stale(ttl));
+
+        Ok(removed)
+    }
+
+    /// Returns place of the participant in its queue, `None` if participant is
+    /// not in any queue.
+    ///
+    /// Position follows the order of arrival, which decides the formation timeout
+    /// and the place of participants returned from an unformed room, but not who
+    /// is taken to the room, as participants are picked in random order.
+    pub async fn position(&self, utxo_id: U256, formation: &Formation<'_>) -> Option<Position> {
         let queues = self.queues.lock().await;
 
-        let Some(queue) = queues.get(&(token, amount)) else {
-            return 0;
+        queues.values().find_map(|queue| {
+            let position = queue.position(utxo_id)?;
+
+            // Counted as in `take`, where the selection doesn't depend on the order
+            // of candidates as long as policies limit participants per owner.
+            let admitted = policy::select(&queue.entries, formation.policies)
+                .len()
+                .min(formation.max_participants);
+
+            Some(Position {
+                position: position + 1,
+                size: queue.entries.len(),
+                admitted,
+                arrival_interval: queue.arrival_interval(),
+                oldest_wait: queue.oldest_wait(),
+            })
+        })
+    }
+
+    /// Returns keys of all queues.
+    pub async fn keys(&self) -> Vec<(Address, U256)> {
+        self.queues.lock().await.keys().cloned().collect()
+    }
+
+    /// Returns keys of the queues with number of participants in them.
+    pub async fn lengths(&self) -> Vec<((Address, U256), usize)> {
+        self.queues
+            .lock()
+            .await
+            .iter()
+            .map(|(key, queue)| (*key, queue.entries.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{storage::MemoryStorage, waiter::OwnerLimit};
+
+    use super::*;
+
+    const MINUTE: u64 = 60;
+
+    fn key() -> (Address, U256) {
+        (Address::repeat_byte(0x11), U256::exp10(18))
+    }
+
+    fn participant(utxo_id: u64) -> Participant {
+        Participant {
+            utxo_id: utxo_id.into(),
+            owner: Address::from_low_u64_be(utxo_id),
+        }
+    }
+
+    fn formation() -> Formation<'static> {
+        Formation {
+            min_participants: 2,
+            max_participants: 3,
+            timeout: Duration::from_secs(MINUTE),
+            policies: &[],
+        }
+    }
+
+    /// Queue restored from the storage with participants `1..` that have
+    /// arrived the given number of seconds ago.
+    async fn queues(waited: &[u64]) -> QueuesStorage {
+        let storage = MemoryStorage::new();
+        let (token, amount) = key();
+
+        for (index, waited) in waited.iter().enumerate() {
+            let participant = participant(index as u64 + 1);
+
+            storage
+                .push_participant(QueueEntry {
+                    token,
+                    amount,
+                    utxo_id: participant.utxo_id,
+                    owner: participant.owner,
+                    joined_at: now() - waited,
+                })
+                .await
+                .unwrap();
+        }
+
+        let queues = QueuesStorage::new(Arc::new(storage));
+        queues.restore().await.unwrap();
+        queues
+    }
+
+    async fn take(queues: &QueuesStorage) -> Option<Taken> {
+        let (token, amount) = key();
+
+        queues.take(token, amount, &formation()).await.unwrap()
+    }
+
+    async fn position(queues: &QueuesStorage, utxo_id: u64) -> Option<usize> {
+        let position = queues.position(utxo_id.into(), &formation()).await?;
+
+        Some(position.position)
+    }
+
+    #[tokio::test]
+    async fn forms_full_room_right_away() {
+        let queues = queues(&[0, 0, 0, 0]).await;
+
+        let taken = take(&queues).await.unwrap();
+
+        assert_eq!(taken.participants.len(), 3);
+        for participant in taken.participants {
+            assert!(queues
+                .position(participant.utxo_id, &formation())
+                .await
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_timeout_to_form_room_of_min_size() {
+        let queues = queues(&[MINUTE - 10, 0]).await;
+
+        assert!(take(&queues).await.is_none());
+        assert_eq!(position(&queues, 1).await, Some(1));
+        assert_eq!(position(&queues, 2).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn forms_room_of_min_size_after_timeout() {
+        let queues = queues(&[MINUTE + 10, 0]).await;
+
+        let taken = take(&queues).await.unwrap();
+
+        assert_eq!(taken.participants.len(), 2);
+        assert_eq!(position(&queues, 1).await, None);
+    }
+
+    #[tokio::test]
+    async fn never_forms_room_below_min_size() {
+        let queues = queues(&[10 * MINUTE]).await;
+
+        assert!(take(&queues).await.is_none());
+        assert_eq!(position(&queues, 1).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn reinserts_participants_by_arrival_time() {
+        let queues = queues(&[4 * MINUTE, 3 * MINUTE, 2 * MINUTE, MINUTE]).await;
+        let (token, amount) = key();
+
+        let taken = take(&queues).await.unwrap();
+        assert_eq!(taken.joined_at.len(), taken.participants.len());
+
+        queues.push(token, amount, participant(5)).await.unwrap();
+        queues
+            .reinsert(token, amount, &taken.participants, &taken.joined_at)
+            .await;
+
+        for utxo_id in 1..=5 {
+            assert_eq!(position(&queues, utxo_id).await, Some(utxo_id as usize));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_utxos_over_owner_limit_queued() {
+        let queues = QueuesStorage::new(Arc::new(MemoryStorage::new()));
+        let (token, amount) = key();
+        let owner = Address::repeat_byte(0x22);
+
+        for utxo_id in 1..=3 {
+            let participant = Participant {
+                utxo_id: utxo_id.into(),
+                owner,
+            };
+            queues.push(token, amount, participant).await.unwrap();
+        }
+        queues.push(token, amount, participant(4)).await.unwrap();
+
+        let policies: Vec<Box<dyn CompositionPolicy>> = vec![Box::new(OwnerLimit::new(1))];
+        let formation = Formation {
+            max_participants: 2,
+            policies: &policies,
+            ..formation()
+        };
+
+        let taken = queues
+            .take(token, amount, &formation)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let owners: Vec<Address> = taken.participants.iter().map(|p| p.owner).collect();
+        assert!(owners.contains(&owner) && owners.contains(&participant(4).owner));
+
+        for utxo_id in 1..=3 {
+            let seated = taken
+                .participants
+                .iter()
+                .any(|p| p.utxo_id == utxo_id.into());
+            assert_eq!(position(&queues, utxo_id).await.is_none(), seated);
+        }
+    }
+
+    #[test]
+    fn picks_participants_in_order_given_by_seed() {
+        let entries: Vec<Participant> = (1..=10).map(participant).collect();
+        let formation = Formation {
+            max_participants: 5,
+            ..formation()
         };
 
-        queue.len()
+        let picked = pick(&entries, [7; 32], &formation);
+
+        assert_eq!(picked.len(), 5);
+        assert_eq!(pick(&entries, [7; 32], &formation), picked);
+        assert_ne!(pick(&entries, [8; 32], &formation), picked);
     }
 }